@@ -1,17 +1,193 @@
-use palc::Parser;
-use regex::RegexSet;
+use palc::{Parser, ValueEnum};
+use regex::{Regex, RegexSet};
 use reqwest::{Client, Proxy};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::OnceLock;
 use std::time::{Duration, Instant};
 use tokio::time::timeout;
+use unicode_width::UnicodeWidthStr;
+
+/// Set when stdout carries machine-readable results, so the progress log and
+/// table move to stderr instead of corrupting them
+static CONSOLE_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Set by `--summary-only` until the summary is reached
+static CONSOLE_MUTED: AtomicBool = AtomicBool::new(false);
+
+/// Retries left for the whole run, lowered by `--retry-budget`
+static RETRY_BUDGET: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Spend one retry of the run's `--retry-budget`; `false` once it is used up
+fn take_retry() -> bool {
+    RETRY_BUDGET
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+            left.checked_sub(1)
+        })
+        .is_ok()
+}
+
+/// `println!` for human-readable console output
+macro_rules! outln {
+    () => {
+        outln!("")
+    };
+    ($($arg:tt)*) => {
+        if !CONSOLE_MUTED.load(Ordering::Relaxed) {
+            let text = console_text(format!($($arg)*));
+            if CONSOLE_TO_STDERR.load(Ordering::Relaxed) {
+                eprintln!("{text}")
+            } else {
+                println!("{text}")
+            }
+        }
+    };
+}
+
+/// `print!` for human-readable console output
+macro_rules! out {
+    ($($arg:tt)*) => {
+        if !CONSOLE_MUTED.load(Ordering::Relaxed) {
+            let text = console_text(format!($($arg)*));
+            if CONSOLE_TO_STDERR.load(Ordering::Relaxed) {
+                eprint!("{text}")
+            } else {
+                print!("{text}")
+            }
+        }
+    };
+}
+
+/// `eprintln!` for errors and warnings, with the same symbol fallback as `outln!`
+macro_rules! errln {
+    ($($arg:tt)*) => {
+        eprintln!("{}", console_text(format!($($arg)*)))
+    };
+}
+
+/// Set by `--no-emoji`, or when the console doesn't look able to show emoji
+static PLAIN_SYMBOLS: AtomicBool = AtomicBool::new(false);
+
+/// Replace emoji and drawing symbols with ASCII while `PLAIN_SYMBOLS` is set
+fn console_text(text: String) -> String {
+    if text.is_ascii() || !PLAIN_SYMBOLS.load(Ordering::Relaxed) {
+        return text;
+    }
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let replacement = match c {
+            '✅' => "[OK]",
+            '❌' => "[X]",
+            '⚠' => "[!]",
+            '↳' | '→' => "->",
+            '≥' => ">=",
+            '—' | '─' => "-",
+            '│' => "|",
+            '█' => "#",
+            '\u{FE0F}' => "",
+            // 其余装饰性 emoji 直接去掉，连同其后的空格
+            c if matches!(c as u32, 0x1F000..=0x1FAFF | 0x2139 | 0x23E9..=0x23FA | 0x2600..=0x27BF) =>
+            {
+                while chars.next_if(|&c| c == ' ' || c == '\u{FE0F}').is_some() {}
+                continue;
+            }
+            c => {
+                plain.push(c);
+                continue;
+            }
+        };
+        plain.push_str(replacement);
+    }
+    plain
+}
+
+/// Whether the console is likely to render emoji and box-drawing characters
+fn console_supports_unicode() -> bool {
+    if cfg!(windows) {
+        // 旧版 conhost 缺少 emoji 字形，Windows Terminal 和 VS Code 终端会设置这些变量
+        return ["WT_SESSION", "TERM_PROGRAM"]
+            .iter()
+            .any(|var| std::env::var_os(var).is_some());
+    }
+    let locale = ["LC_ALL", "LC_CTYPE", "LANG"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok().filter(|value| !value.is_empty()));
+    match locale {
+        Some(locale) => {
+            let locale = locale.to_ascii_lowercase();
+            locale.contains("utf-8") || locale.contains("utf8")
+        }
+        None => true,
+    }
+}
 
 #[derive(Parser)]
 #[command(name = "proxy-speedtest")]
 #[command(long_about = "Test SingBox proxy nodes latency and download speed")]
 struct Args {
     /// Path to the SingBox config JSON file
-    config_path: String,
+    ///
+    /// Falls back to the `PROXY_SPEEDTEST_CONFIG` environment variable when omitted;
+    /// not needed with `--socks`
+    config_path: Option<String>,
+
+    /// Preset of test settings; flags given explicitly take precedence
+    ///
+    /// `fast`: 5 latency probes, no download, 4 nodes at a time.
+    /// `accurate`: 20 probes, 3 downloads of 10 MB after a warmup, retry unstable nodes.
+    /// `thorough`: 30 probes, a 1/10/50 MB sweep, 3 downloads, loaded latency,
+    /// a load test at 1 and 4 connections, retry unstable nodes
+    #[arg(long, value_enum)]
+    profile: Option<Profile>,
+
+    /// Probe non-socks inbounds (http, mixed, mislabeled ones) and test them too
+    ///
+    /// Each local port is tried as socks5 and then as an HTTP proxy, with a CONNECT
+    /// to `--probe-host`; whichever handshake succeeds is used for that port
+    #[arg(long)]
+    autodetect_protocol: bool,
+
+    /// Which entry of an inbound's `users` to authenticate as
+    ///
+    /// Inbounds that declare `users` are tested with those credentials; an index
+    /// past the end falls back to the first user
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    user_index: usize,
+
+    /// Download the SingBox config from this URL instead of reading a file
+    #[arg(long, value_name = "URL", conflicts_with = "config_path")]
+    config_url: Option<String>,
+
+    /// Extra header for `--config-url`, e.g. `Authorization: Bearer <token>`; repeatable
+    #[arg(long, value_name = "NAME: VALUE")]
+    config_header: Vec<String>,
+
+    /// Test a socks5 proxy at this address instead of a config's inbounds; repeatable
+    ///
+    /// No config is read then. Each proxy is tagged with its address and may be on
+    /// another machine; the port column shows its position in the list instead
+    #[arg(long, value_name = "HOST:PORT", conflicts_with = "config_url")]
+    socks: Vec<ProbeHost>,
+
+    /// Test the outbounds of a selector through one socks inbound, via SingBox's Clash API
+    ///
+    /// E.g. `--clash-api http://127.0.0.1:9090 --clash-selector proxy`. Each of the
+    /// selector's outbounds is selected in turn and tested; `-w`/`-b` then match
+    /// outbound tags. The secret is read from `experimental.clash_api.secret`
+    #[arg(long, value_name = "URL", requires = "clash_selector")]
+    clash_api: Option<String>,
+
+    /// Selector outbound switched by `--clash-api`
+    #[arg(long, value_name = "TAG", requires = "clash_api")]
+    clash_selector: Option<String>,
+
+    /// Tag of the socks inbound used by `--clash-api`; defaults to the first local one
+    #[arg(long, value_name = "TAG", requires = "clash_api")]
+    clash_inbound: Option<String>,
 
     /// Regex pattern to filter node tags, whitelist
     ///
@@ -33,501 +209,6672 @@ struct Args {
     #[arg(short, long)]
     blacklist_patterns: Vec<String>,
 
-    /// Download test size in MB (optional, enables speed test if provided)
-    #[arg(short = 'd', long = "download-mb")]
-    download_mb: Option<u32>,
-}
+    /// Only test inbounds whose tag matches a regex read from stdin, one per line
+    ///
+    /// Blank lines are ignored and the patterns still combine with `-w`/`-b`,
+    /// e.g. `other-tool | proxy-speedtest config.json --tags-stdin`
+    #[arg(long)]
+    tags_stdin: bool,
 
-#[derive(Debug, Deserialize)]
-struct Config {
-    inbounds: Option<Vec<Inbound>>,
-}
+    /// Match the `--tags-stdin` lines as exact tags instead of regexes
+    #[arg(long, requires = "tags_stdin")]
+    tags_literal: bool,
 
-#[derive(Debug, Deserialize)]
-struct Inbound {
-    #[serde(rename = "type")]
-    inbound_type: Option<String>,
-    tag: Option<String>,
-    listen_port: Option<u16>,
-    listen: Option<String>,
-}
+    /// Also print per-group aggregates, grouping tags by the regex's first capture
+    ///
+    /// E.g. `--group-by '^([A-Z]+)-'` groups `US-01` and `US-02` under `US`;
+    /// tags that don't match go into the `other` group
+    #[arg(long, value_name = "REGEX")]
+    group_by: Option<String>,
 
-#[derive(Debug, Clone)]
-enum LatencyResult {
-    Success {
-        median: f64,
-        average: f64,
-        minimum: f64,
-        maximum: f64,
-    },
-    Unstable(usize, usize), // valid_count, total_count
-    AllFailed,
-    SessionError(String),
-}
+    /// JSON object mapping inbound tags to display names, e.g. `{"in-3f2a": "US-01"}`
+    ///
+    /// Applied after `-w`/`-b` matched the original tags, so filters keep working
+    /// on the config's tags while all output uses the friendlier names
+    #[arg(long, value_name = "JSON")]
+    alias_file: Option<String>,
 
-#[derive(Debug, Clone)]
-enum SpeedResult {
-    Success(f64), // Speed in Mbps
-    Failed(String),
-}
+    /// Ask for confirmation before testing more than N nodes
+    ///
+    /// Guards against an overly broad pattern starting an hours-long run; without a
+    /// terminal to ask on, the run is aborted unless `--yes` is given
+    #[arg(long, value_name = "N")]
+    confirm_over: Option<usize>,
 
-#[derive(Debug, Clone)]
-struct NodeResult {
-    tag: String,
-    port: u16,
-    latency: LatencyResult,
-    speed: Option<SpeedResult>,
-}
+    /// Answer yes to the `--confirm-over` prompt
+    #[arg(short = 'y', long)]
+    yes: bool,
 
-impl std::fmt::Display for LatencyResult {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            LatencyResult::Success {
-                median,
-                average,
-                maximum,
-                minimum,
-            } => write!(f, "{median:.2}/{average:.2}/{minimum:.2}/{maximum:.2}"),
-            LatencyResult::Unstable(valid, total) => write!(f, "Unstable ({}/{})", valid, total),
-            LatencyResult::AllFailed => write!(f, "All Failed"),
-            LatencyResult::SessionError(err) => write!(f, "Session Error: {}", err),
-        }
-    }
-}
+    /// Download test size, e.g. `500KB`, `10MB`, `1.5GB` (enables speed test if provided)
+    ///
+    /// Units are binary (1 MB = 1024 KB) and a bare number means MB, matching the
+    /// old `-d <MB>`. The body is streamed rather than buffered, so there is no upper limit.
+    /// Several sizes, e.g. `1MB,10MB,100MB`, run a sweep showing how throughput ramps
+    /// with size; the largest one is the speed used for ranking
+    ///
+    /// Besides the average over the whole download, the peak sustained speed over
+    /// any 2 s window is reported, which leaves out TCP slow-start
+    #[arg(short = 'd', long, value_name = "SIZE", value_delimiter = ',')]
+    download_size: Vec<ByteSize>,
 
-impl std::fmt::Display for SpeedResult {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            SpeedResult::Success(speed) => write!(f, "{:.2} Mbps", speed),
-            SpeedResult::Failed(err) => write!(f, "Failed: {}", err),
-        }
-    }
-}
+    /// Re-run the download with double the size until it takes at least this many seconds
+    ///
+    /// Guards against fast nodes finishing a small `-d` too quickly to measure
+    /// accurately; the size is capped at 1 GB
+    #[arg(long, value_name = "SECS")]
+    min_download_time: Option<f64>,
 
-async fn test_node_latency(port: u16, test_count: usize) -> LatencyResult {
-    let url = "https://www.cloudflare.com/cdn-cgi/trace";
-    let proxy_url = format!("socks5h://127.0.0.1:{}", port);
+    /// Run the download this many times and report the median throughput
+    ///
+    /// Smooths out CDN and routing variance the way repeated latency probes do;
+    /// the best run is shown too and every sample is kept in the JSON output [default: 1]
+    #[arg(long, value_name = "N")]
+    speed_samples: Option<usize>,
 
-    let proxy = match Proxy::all(&proxy_url) {
-        Ok(proxy) => proxy,
-        Err(e) => return LatencyResult::SessionError(format!("Failed to create proxy: {}", e)),
-    };
+    /// Minimum download speed in Mbps for the speed test to count as a success
+    ///
+    /// Slower completed downloads are reported as `Too Slow` and ranked together
+    /// with failed ones, instead of above them
+    #[arg(long, value_name = "MBPS")]
+    speed_floor: Option<f64>,
 
-    let client = Client::builder()
-        .proxy(proxy)
-        .timeout(Duration::from_secs(10))
-        .connect_timeout(Duration::from_secs(5))
-        .build();
+    /// Stop a download as soon as it is clearly slower than `--speed-floor`
+    ///
+    /// After a few seconds, a download averaging under half the floor is cut off
+    /// and reported as `Too Slow`, instead of fetching the whole file
+    #[arg(long, requires = "speed_floor")]
+    abort_below_floor: bool,
 
-    let client = match client {
-        Ok(client) => client,
-        Err(e) => return LatencyResult::SessionError(format!("Failed to create client: {}", e)),
-    };
+    /// Run the latency and speed tests of a node at the same time
+    ///
+    /// Roughly halves the time per node, but latency is then measured while the
+    /// download loads the link, so it reads higher than an idle measurement
+    #[arg(long)]
+    overlap_probes: bool,
 
-    let mut latencies = Vec::new();
+    /// Download in N byte ranges at once through the node, summing their speed
+    ///
+    /// Closer to a multi-threaded download manager than a single stream. Needs the
+    /// download URL to answer with `Accept-Ranges: bytes`; otherwise the usual
+    /// single download is done
+    #[arg(long, value_name = "N")]
+    segments: Option<usize>,
 
-    println!("  预热连接...");
-    let _ = timeout(Duration::from_secs(10), client.head(url).send()).await;
+    /// Show the TLS version and cipher suite negotiated with the latency URL's host
+    ///
+    /// The handshake runs through the node and checks the certificate against the
+    /// system's roots, revealing nodes that downgrade or intercept TLS
+    #[arg(long)]
+    show_tls: bool,
 
-    for i in 0..test_count {
-        let start = Instant::now();
-        let result = timeout(Duration::from_secs(10), client.head(url).send()).await;
+    /// Deprecated: use `--download-size`. Download test size in whole MB; comma-separated
+    /// sizes run a sweep like `-d`
+    #[arg(
+        long = "download-mb",
+        conflicts_with = "download_size",
+        value_delimiter = ','
+    )]
+    download_mb: Vec<u32>,
 
-        match result {
-            Ok(Ok(response)) => {
-                if response.status().is_success() {
-                    let elapsed_ms = start.elapsed().as_micros() as f64 / 1000.0;
-                    latencies.push(elapsed_ms);
-                    println!("  ↳ 第 {:2} 次: {:6.2} ms", i + 1, elapsed_ms);
-                } else {
-                    latencies.push(f64::INFINITY);
-                    println!("  ↳ 第 {:2} 次: HTTP Error {}", i + 1, response.status());
-                    break;
-                }
-            }
-            Ok(Err(e)) => {
-                latencies.push(f64::INFINITY);
-                println!("  ↳ 第 {:2} 次: Error ({})", i + 1, e);
-                break;
-            }
-            Err(_) => {
-                latencies.push(f64::INFINITY);
-                println!("  ↳ 第 {:2} 次: Timeout", i + 1);
-                break;
-            }
-        }
-    }
+    /// Unit used to display download speed
+    ///
+    /// `mbps` = megabits/s, `mibps` = MiB/s, `mbytes` = MB/s
+    #[arg(long, value_enum, default_value_t = SpeedUnit::Mbps)]
+    speed_unit: SpeedUnit,
 
-    if latencies.is_empty() || latencies.iter().all(|&l| l.is_infinite()) {
-        return LatencyResult::AllFailed;
-    }
+    /// Concurrency levels for a per-node load test, e.g. `1,4,16`
+    ///
+    /// At each level that many latency probes (and downloads, if `-d` is given)
+    /// run in parallel through the node, showing how it scales under load
+    #[arg(long, value_delimiter = ',')]
+    load_test: Vec<usize>,
 
-    let valid_latencies: Vec<f64> = latencies
-        .into_iter()
-        .filter(|&l| !l.is_infinite())
-        .collect();
+    /// Nodes whose latency is tested at the same time
+    ///
+    /// Values above 1 here or in `--speed-concurrency` split the run into two phases:
+    /// latency (and every other probe) for all nodes, then the downloads for all
+    /// nodes, so parallel downloads never load the link while latency is measured.
+    /// `--overlap-probes` is ignored then, and `--node-budget` applies to each phase
+    /// [default: 1]
+    #[arg(long, value_name = "N")]
+    latency_concurrency: Option<usize>,
 
-    if valid_latencies.len() < 3 {
-        return LatencyResult::Unstable(valid_latencies.len(), test_count);
-    }
+    /// Nodes whose download speed is tested at the same time; see `--latency-concurrency`
+    ///
+    /// Parallel downloads share the local link, so each node's speed reads lower
+    /// [default: 1]
+    #[arg(long, value_name = "N")]
+    speed_concurrency: Option<usize>,
 
-    let mut sorted = valid_latencies;
-    sorted.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
-    let median = sorted[sorted.len() / 2];
-    let average = sorted.iter().sum::<f64>() / sorted.len() as f64;
+    /// Spread the start of the first concurrent tests over this many seconds
+    ///
+    /// Avoids opening every node's connections in the same instant, which can
+    /// itself cause failures at high concurrency; starts are jittered within the
+    /// window. Only matters with `--latency-concurrency`/`--speed-concurrency`
+    #[arg(long, value_name = "SECS")]
+    ramp: Option<f64>,
 
-    LatencyResult::Success {
-        median,
-        average,
-        minimum: *sorted.first().unwrap(),
-        maximum: *sorted.last().unwrap(),
-    }
-}
+    /// Checkpoint file for resuming an interrupted run
+    ///
+    /// Finished nodes are saved as they complete; re-running with the same file
+    /// skips them. The file is rejected if the config or test parameters changed
+    #[arg(long, value_name = "STATE_JSON")]
+    resume: Option<String>,
 
-async fn test_node_speed(port: u16, size_mb: u32) -> SpeedResult {
-    let proxy_url = format!("socks5h://127.0.0.1:{}", port);
+    /// Do a small warmup download before the measured one
+    ///
+    /// The measured throughput then excludes the TLS handshake and TCP slow-start
+    #[arg(long)]
+    speed_warmup: bool,
 
-    let proxy = match Proxy::all(&proxy_url) {
-        Ok(proxy) => proxy,
-        Err(e) => return SpeedResult::Failed(format!("Failed to create proxy: {}", e)),
-    };
+    /// Skip the warmup download even when `--profile` turns it on
+    #[arg(long, conflicts_with = "speed_warmup")]
+    no_warmup_speed: bool,
 
-    let client = Client::builder()
-        .proxy(proxy)
-        .timeout(Duration::from_secs(60))
-        .connect_timeout(Duration::from_secs(10))
-        .build();
+    /// Skip the warmup request made before the latency probes
+    ///
+    /// The first probe then pays for a fresh connection and handshake
+    #[arg(long)]
+    no_warmup_latency: bool,
 
-    let client = match client {
-        Ok(client) => client,
-        Err(e) => return SpeedResult::Failed(format!("Failed to create client: {}", e)),
-    };
+    /// Print the warmup measurements instead of discarding them
+    ///
+    /// The warmup runs on a cold connection, so it shows the full handshake cost
+    /// next to the warm numbers that follow
+    #[arg(long)]
+    report_warmup: bool,
 
-    let test_url = if size_mb <= 1024 {
-        format!(
-            "https://speed.cloudflare.com/__down?bytes={}",
-            size_mb * 1024 * 1024
-        )
-    } else {
-        return SpeedResult::Failed("Size too large (>1GB not supported)".to_string());
-    };
+    /// Reject the config if it contains keys unknown to SingBox
+    ///
+    /// Checks top-level keys and the fields of socks/http/mixed inbounds, which
+    /// helps catch typos such as `listen_prot` in hand-edited configs
+    #[arg(long)]
+    strict_json: bool,
 
-    println!("  开始下载测试 ({} MB)...", size_mb);
-    let start = Instant::now();
+    /// Show a letter grade (A/B/C/D/F) per node as the leading table column
+    #[arg(long)]
+    grade: bool,
 
-    let result = timeout(Duration::from_secs(120), client.get(test_url).send()).await;
+    /// Median latency upper bounds in ms for grades A, B, C and D
+    ///
+    /// Slower or failed nodes get F
+    #[arg(long, value_delimiter = ',', value_name = "MS,MS,MS,MS")]
+    grade_latency: Vec<f64>,
 
-    match result {
-        Ok(Ok(response)) => {
-            if response.status().is_success() {
-                match response.bytes().await {
-                    Ok(bytes) => {
-                        let elapsed = start.elapsed();
-                        let bytes_downloaded = bytes.len() as f64;
-                        let megabits = (bytes_downloaded * 8.0) / 1_000_000.0;
-                        let seconds = elapsed.as_secs_f64();
-                        let speed_mbps = megabits / seconds;
-
-                        println!(
-                            "  ↳ 下载完成: {:.2} MiB in {:.2}s → {:.2} Mbps",
-                            bytes_downloaded / 1024.0 / 1024.0,
-                            seconds,
-                            speed_mbps
-                        );
-                        SpeedResult::Success(speed_mbps)
-                    }
-                    Err(e) => SpeedResult::Failed(format!("Failed to read response: {}", e)),
-                }
-            } else {
-                SpeedResult::Failed(format!("HTTP Error: {}", response.status()))
-            }
-        }
-        Ok(Err(e)) => SpeedResult::Failed(format!("Request error: {}", e)),
-        Err(_) => SpeedResult::Failed("Timeout".to_string()),
-    }
-}
+    /// Download speed lower bounds in Mbps for grades A, B, C and D
+    ///
+    /// Only applies when the speed test is enabled; slower or failed downloads get F
+    #[arg(long, value_delimiter = ',', value_name = "MBPS,MBPS,MBPS,MBPS")]
+    grade_speed: Vec<f64>,
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let Args {
-        config_path,
-        download_mb,
-        whitelist_patterns,
-        blacklist_patterns,
-    } = Args::parse();
+    /// URL requested by the HTTP latency probe
+    ///
+    /// Must be `https://` unless `--danger-allow-http` is given
+    #[arg(long, value_name = "URL", default_value_t = LATENCY_URL.to_string())]
+    latency_url: String,
 
-    let whitelist_patterns = RegexSet::new(whitelist_patterns)?;
-    let blacklist_patterns = RegexSet::new(blacklist_patterns)?;
+    /// User-Agent header for all requests sent through the nodes
+    ///
+    /// Useful when a node's upstream blocks reqwest's default agent with 403s
+    #[arg(long, value_name = "STRING")]
+    user_agent: Option<String>,
 
-    let config_content = match fs::read_to_string(&config_path) {
-        Ok(content) => content,
-        Err(e) => {
-            eprintln!("❌ 无法读取 JSON 文件: {}", e);
-            return Ok(());
-        }
-    };
+    /// Allow plain `http://` test URLs
+    ///
+    /// Traffic to the target is then readable by the proxy and anyone on its path,
+    /// but diffing against HTTPS shows the TLS handshake's share of the latency
+    #[arg(long)]
+    danger_allow_http: bool,
 
-    let config: Config = match serde_json::from_str(&config_content) {
-        Ok(config) => config,
-        Err(e) => {
-            eprintln!("❌ JSON 解析失败: {}", e);
-            return Ok(());
-        }
-    };
+    /// Probe each node against the URL in its inbound's `speedtest_url` field
+    ///
+    /// Lets region-specific nodes be measured against a nearby endpoint instead of
+    /// the one global `--latency-url`, which nodes without the field still use
+    #[arg(long)]
+    respect_node_target: bool,
 
-    let inbounds = match config.inbounds {
-        Some(inbounds) => inbounds,
-        None => {
-            eprintln!("❌ 未找到 inbounds 字段");
-            return Ok(());
-        }
-    };
+    /// Skip certificate verification of the test URLs
+    ///
+    /// Lets nodes that intercept TLS, or whose upstream uses a self-signed
+    /// certificate, be measured instead of failing with a TLS error
+    #[arg(long)]
+    danger_accept_invalid_certs: bool,
 
-    let mut socks_nodes = Vec::new();
-    for inbound in inbounds {
-        if let (Some(inbound_type), Some(tag), Some(port), listen) = (
-            inbound.inbound_type,
-            inbound.tag,
-            inbound.listen_port,
-            inbound.listen,
-        ) {
-            if inbound_type == "socks" {
-                let listen_addr = listen.unwrap_or_else(|| "127.0.0.1".to_string());
+    /// Whether hostnames resolved locally are cached between requests
+    ///
+    /// Off by default, so every new connection resolves afresh. Requests through a
+    /// node never resolve locally (`socks5h` and HTTP CONNECT leave that to the node),
+    /// so this only affects direct requests, currently the `--leak-check` lookup
+    #[arg(long, value_enum, default_value_t = DnsCache::Off, value_name = "MODE")]
+    dns_cache: DnsCache,
 
-                let tag_matches = whitelist_patterns.matches(&tag).matched_all()
-                    && (blacklist_patterns.is_empty()
-                        || !blacklist_patterns.matches(&tag).matched_all());
-                if tag_matches && matches!(listen_addr.as_str(), "127.0.0.1" | "::1" | "localhost")
-                {
-                    socks_nodes.push((tag, port));
-                }
-            }
-        }
-    }
+    /// Measure latency as a proxy CONNECT to `--probe-host` instead of an HTTPS request
+    #[arg(long)]
+    tcp_ping: bool,
 
-    if socks_nodes.is_empty() {
-        if whitelist_patterns.is_empty() {
-            eprintln!("❌ 未找到任何 socks 类型的 inbound");
-        } else {
-            eprintln!("❌ 未找到匹配正则表达式的 socks 节点");
-            eprintln!("   白名单正则: {whitelist_patterns:?}");
-            eprintln!("   黑名单正则: {blacklist_patterns:?}");
-        }
-        return Ok(());
-    }
+    /// Target of the tcp-ping probe
+    #[arg(long, value_name = "HOST:PORT", default_value = "1.1.1.1:443")]
+    probe_host: ProbeHost,
 
-    let test_description = if let Some(size) = download_mb {
-        format!(
-            "找到 {} 个 socks 节点，开始顺序测试（延迟测试10次 + 下载测试 {} MB）\n",
-            socks_nodes.len(),
-            size
-        )
-    } else {
-        format!(
-            "找到 {} 个 socks 节点，开始顺序测试（每节点10次延迟测试）\n",
-            socks_nodes.len()
+    /// Print each node's result line as soon as it finishes
+    ///
+    /// The sorted table is still printed at the end
+    #[arg(long)]
+    stream_results: bool,
+
+    /// Shell command run after each node finishes, for notifications or custom logic
+    ///
+    /// The result is passed in the `TAG`, `PORT`, `MEDIAN`, `SPEED` (Mbps) and `STATUS`
+    /// environment variables. A hook that fails or runs past 30s is reported and skipped
+    #[arg(long, value_name = "CMD")]
+    on_result: Option<String>,
+
+    /// Number of latency probes per node [default: 10]
+    #[arg(long)]
+    latency_count: Option<usize>,
+
+    /// Pause between latency attempts, in ms
+    ///
+    /// Lets connections go idle between probes, like intermittent browsing, so
+    /// keep-alive eviction and connection revival show up in the numbers
+    #[arg(long, value_name = "MS")]
+    think_time: Option<u64>,
+
+    /// Add a small random jitter to the pause and timeout of each probe attempt
+    ///
+    /// Timeouts grow by up to 10% and attempts are spaced by up to 10% of
+    /// `--think-time` (or 50 ms without it), so runs sharing upstream
+    /// infrastructure don't time out and retry in lockstep
+    #[arg(long)]
+    jitter: bool,
+
+    /// Successful latency samples required to report stats instead of "Unstable"
+    ///
+    /// Defaults to 3 (the historical cutoff), capped at `--latency-count`
+    #[arg(long, conflicts_with = "min_valid_ratio")]
+    min_valid: Option<usize>,
+
+    /// Like `--min-valid`, but as a fraction of `--latency-count`, e.g. `0.5`
+    #[arg(long)]
+    min_valid_ratio: Option<f64>,
+
+    /// Also measure latency while a background download saturates the node
+    ///
+    /// Reports idle vs. loaded latency and their difference (bufferbloat)
+    #[arg(long)]
+    latency_under_load: bool,
+
+    /// Skip the loaded-latency measurement even when `--profile` turns it on
+    #[arg(long, conflicts_with = "latency_under_load")]
+    no_latency_under_load: bool,
+
+    /// Result formats, comma-separated: `table`, `table-compact`, `json`, `csv`,
+    /// `ranking`, `ndjson`
+    ///
+    /// `table-compact` replaces the table with one borderless line per node, e.g.
+    /// `#1 52ms 120Mbps [US-01]`, for narrow terminals and logs.
+    /// `ranking` is just the ranked tags as a JSON array, e.g. `["US-01","JP-02"]`.
+    /// `ndjson` writes one unranked result object per line as each node finishes;
+    /// a node rescued by `--retry-unstable` appears again with its new result
+    ///
+    /// The table is always shown on the console. Other formats go to stdout (the
+    /// console log then moves to stderr), at most one at a time, or to files with
+    /// `--output-dir`, where `table` and `table-compact` are saved as well
+    #[arg(long, value_enum, value_delimiter = ',')]
+    format: Vec<OutputFormat>,
+
+    /// Columns of the result table and CSV export, in order, e.g. `tag,port,median,speed`
+    ///
+    /// Replaces the default set. Columns that only exist in the table (`grade`,
+    /// `exit-ip`, `country`, `dns`, `http`, `tls`, `health`, `baseline`) are left out of
+    /// the CSV
+    #[arg(long, value_enum, value_delimiter = ',')]
+    columns: Vec<Column>,
+
+    /// How results are ranked
+    ///
+    /// `stable-fast` prefers a reliable node over a faster flaky one: nodes that lost
+    /// more than 20% of their latency probes are ranked last, the rest by median latency
+    #[arg(long, value_enum, default_value_t = Selection::Rank)]
+    select: Selection,
+
+    /// Keep results in config order instead of ranking them
+    ///
+    /// The rank column then shows each node's position among the config's socks nodes
+    #[arg(long)]
+    no_sort: bool,
+
+    /// Rank problem nodes first, worst problem at the top
+    ///
+    /// Nodes with the same problem keep their usual order among themselves; healthy
+    /// nodes follow in the normal ranking
+    #[arg(long, conflicts_with = "no_sort")]
+    failures_first: bool,
+
+    /// Order of the json and csv exports, independent of the table ranking
+    ///
+    /// Exported rows keep their `rank` from the table; `ranking` output is always in
+    /// rank order. Defaults to the table's order
+    #[arg(long, value_enum, value_name = "FIELD")]
+    export_sort: Option<ExportSort>,
+
+    /// Only show (and export) the best N nodes
+    #[arg(long, value_name = "N")]
+    top: Option<usize>,
+
+    /// Results of an earlier run (`--format json`) to compare against, matched by tag
+    ///
+    /// Adds a column with each node's change in median latency and speed, and
+    /// lists the baseline's nodes that were not tested this time
+    #[arg(long, value_name = "RESULTS_JSON")]
+    baseline: Option<String>,
+
+    /// Only test the best N nodes of the `--baseline` results, skipping the rest
+    ///
+    /// Quickly re-verifies the current favorites; tags are matched against the
+    /// config's inbounds after `--alias-file` is applied
+    #[arg(long, value_name = "N")]
+    retest_top: Option<usize>,
+
+    /// Exit with an error before testing if fewer than N nodes matched
+    ///
+    /// Catches a subscription update that silently dropped nodes, or filters that
+    /// no longer match what they used to
+    #[arg(long, value_name = "N")]
+    min_nodes: Option<usize>,
+
+    /// With `--baseline`, only show nodes whose latency or speed moved by more than
+    /// this many percent, or whose status changed; new nodes always show
+    ///
+    /// Only the console table is filtered, exports still contain every node
+    #[arg(long, value_name = "PERCENT")]
+    only_changed: Option<f64>,
+
+    /// Keep a long-term health record per node in this JSON file, matched by tag
+    ///
+    /// Each run folds its results into a moving average of success rate and median
+    /// latency, shown in a health column; the file is created if missing
+    #[arg(long, value_name = "FILE")]
+    health_file: Option<String>,
+
+    /// Weight of the current run in the `--health-file` averages, in (0, 1]
+    ///
+    /// Higher values react faster to change, lower ones smooth out noisy runs
+    #[arg(
+        long,
+        value_name = "ALPHA",
+        default_value_t = 0.3,
+        requires = "health_file"
+    )]
+    ewma_alpha: f64,
+
+    /// Write each non-table format to `<DIR>/results.<ext>` instead of stdout
+    #[arg(long, value_name = "DIR")]
+    output_dir: Option<String>,
+
+    /// Write the nodes that failed, were unstable or too slow to this file instead
+    ///
+    /// Uses the first non-table `--format` (json otherwise), and leaves those nodes
+    /// out of the regular exports; the console table still lists every node
+    #[arg(long, value_name = "PATH")]
+    failures_output: Option<String>,
+
+    /// Only print the final summary, without per-node logs or the result table
+    ///
+    /// Errors are still reported, and `--format` exports are unaffected
+    #[arg(long)]
+    summary_only: bool,
+
+    /// Print the fully resolved options and the nodes that would be tested as JSON, then exit
+    ///
+    /// Shows the effect of defaults, `PROXY_SPEEDTEST_CONFIG` and deprecated flags;
+    /// nothing is tested and `--leak-check` does not fetch the direct IP
+    #[arg(long)]
+    print_config: bool,
+
+    /// Number format of the console output and table, e.g. `de-DE` for `1.234,56`
+    ///
+    /// Only the language part is used. JSON and CSV exports are unaffected
+    #[arg(long, value_name = "LOCALE")]
+    locale: Option<NumberLocale>,
+
+    /// Border style of result tables
+    #[arg(long, value_enum, default_value_t = TableStyle::Minimal)]
+    table_style: TableStyle,
+
+    /// Print ASCII stand-ins instead of emoji and box-drawing characters
+    ///
+    /// Turned on automatically when the console doesn't look able to show them:
+    /// a non-UTF-8 locale, or the legacy Windows console
+    #[arg(long)]
+    no_emoji: bool,
+
+    /// Measure how long the proxy takes to resolve a hostname
+    ///
+    /// Times one request by hostname and one by IP literal to the same endpoint;
+    /// the difference is reported as `dns_ms`
+    #[arg(long)]
+    dns_probe: bool,
+
+    /// Check whether each node can reach an IPv6-only destination
+    ///
+    /// Requests an IPv6 literal through the node and reports `ipv6_ok`; many
+    /// proxies only have IPv4 egress
+    #[arg(long)]
+    check_ipv6: bool,
+
+    /// Compare each node's exit IP with the direct (unproxied) IP
+    ///
+    /// A node whose exit IP equals the direct one is flagged `LEAK?`, since the
+    /// proxy may be failing open to a direct connection
+    #[arg(long)]
+    leak_check: bool,
+
+    /// Only keep nodes whose detected exit country is one of these, e.g. `US,JP`
+    ///
+    /// The country comes from the trace endpoint, so this filters after testing;
+    /// it is more reliable than matching the tag name
+    #[arg(long, value_delimiter = ',', value_name = "CC,CC")]
+    country: Vec<String>,
+
+    /// Local IP address that direct (unproxied) requests originate from
+    ///
+    /// Only affects direct traffic, currently the `--leak-check` reference lookup,
+    /// so multi-homed machines can pick the intended WAN interface
+    #[arg(long, value_name = "IP")]
+    local_address: Option<std::net::IpAddr>,
+
+    /// Also report a trimmed mean of the latency samples
+    ///
+    /// Less sensitive to a single slow outlier than the plain average
+    #[arg(long)]
+    robust: bool,
+
+    /// Also report a 95% confidence interval for the mean latency
+    ///
+    /// Shown as `±ms` around the average: 50±2 ms is a trustworthy number, 50±40
+    /// needs more samples
+    #[arg(long)]
+    ci: bool,
+
+    /// Fraction of samples dropped from each end for `--robust`
+    #[arg(long, default_value_t = 0.1, value_name = "FRACTION")]
+    trim_fraction: f64,
+
+    /// Send N latency requests per connection, then start a fresh one
+    ///
+    /// Skips the warmup request and reports first-on-connection vs. reused latency
+    /// separately, showing how much of the latency is handshake cost
+    #[arg(long, value_name = "N")]
+    reuse: Option<usize>,
+
+    /// Cap on the total time spent testing one node, in seconds
+    ///
+    /// Once used up, the node's remaining probes are skipped and it is classified
+    /// from the samples gathered so far
+    #[arg(long, value_name = "SECS")]
+    node_budget: Option<f64>,
+
+    /// Print an ASCII histogram of each node's latency samples
+    #[arg(long)]
+    histogram: bool,
+
+    /// Bucket width of `--histogram` in ms
+    #[arg(long, default_value_t = 20.0, value_name = "MS")]
+    histogram_bucket: f64,
+
+    /// Decimal places for latencies and speeds in the console output and table
+    ///
+    /// JSON and CSV exports are unaffected
+    #[arg(long, default_value_t = 2, value_name = "N")]
+    precision: usize,
+
+    /// Re-test nodes that came back unstable or with a session error once more
+    /// after the first pass, keeping the retry result if it succeeds
+    #[arg(long)]
+    retry_unstable: bool,
+
+    /// Don't re-test unstable nodes even when `--profile` turns it on
+    #[arg(long, conflicts_with = "retry_unstable")]
+    no_retry_unstable: bool,
+
+    /// Cap on retries the whole run may spend, across all nodes and probe types
+    ///
+    /// Counts `--retry-unstable` node re-tests and `--honor-retry-after` /
+    /// `--retry-transient` request retries; once used up, remaining failures are not
+    /// retried
+    #[arg(long, value_name = "N")]
+    retry_budget: Option<usize>,
+
+    /// Developer tool: measure the tool's own timing overhead against an in-process
+    /// server and check the stats math, then exit
+    #[arg(long, hide = true)]
+    self_bench: bool,
+
+    /// On `429 Too Many Requests`, wait for `Retry-After` and retry the request once
+    ///
+    /// Keeps bursty runs against a rate-limited endpoint from failing good nodes;
+    /// a wait that would outlast `--node-budget` is not retried
+    #[arg(long)]
+    honor_retry_after: bool,
+
+    /// Retry a probe request once after a second when it fails transiently
+    ///
+    /// Covers timeouts, reset connections and 502/503/504; errors such as 400, 404 or
+    /// an unusable proxy are never retried. A dead node then costs twice the timeout
+    /// per probe, so pair it with `--retry-budget`
+    #[arg(long)]
+    retry_transient: bool,
+
+    /// HTTP status codes counted as a successful latency probe
+    ///
+    /// Comma-separated codes or inclusive ranges, e.g. `200-399` or `200-299,301,302`
+    #[arg(long, default_value = "200-299")]
+    accept_status: StatusRanges,
+
+    /// Keep timing responses whose status is not accepted instead of failing the node
+    ///
+    /// An error response still proves the path works, so its round trip counts as a
+    /// latency sample; the status is flagged separately in an `HTTP` column
+    #[arg(long)]
+    measure_error_latency: bool,
+
+    /// Count a timed-out latency probe as a sample at the timeout instead of a failure
+    ///
+    /// With a generous timeout, a node that is alive but consistently slow then gets
+    /// a (capped) latency instead of being marked failed
+    #[arg(long)]
+    timeout_as_slow: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Selection {
+    /// Fastest download, then lowest median latency
+    Rank,
+    /// Nodes losing more than 20% of latency probes go last, the rest by median latency
+    StableFast,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ExportSort {
+    /// Same order as the table
+    Rank,
+    /// Alphabetical by tag, stable across runs for diffing
+    Tag,
+    Port,
+    /// Lowest median latency first
+    Latency,
+    /// Fastest download first, then lowest median latency
+    Speed,
+}
+
+impl ExportSort {
+    /// Reorder `(rank, result)` rows; ties keep their rank order
+    fn apply(self, rows: &mut [(usize, &NodeResult)]) {
+        match self {
+            ExportSort::Rank => rows.sort_by_key(|(rank, _)| *rank),
+            ExportSort::Tag => rows.sort_by(|(_, a), (_, b)| a.tag.cmp(&b.tag)),
+            ExportSort::Port => rows.sort_by_key(|(_, result)| result.port),
+            ExportSort::Latency => rows.sort_by(|(_, a), (_, b)| rank_order(a, b, false)),
+            ExportSort::Speed => rows.sort_by(|(_, a), (_, b)| rank_order(a, b, true)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Profile {
+    Fast,
+    Accurate,
+    Thorough,
+}
+
+/// Settings a `--profile` supplies for flags that were not given
+struct Preset {
+    latency_count: usize,
+    download_sizes: &'static [u64],
+    speed_samples: usize,
+    speed_warmup: bool,
+    retry_unstable: bool,
+    latency_under_load: bool,
+    load_test: &'static [usize],
+    latency_concurrency: usize,
+}
+
+impl Preset {
+    /// The historical defaults, used without `--profile`
+    const DEFAULT: Preset = Preset {
+        latency_count: 10,
+        download_sizes: &[],
+        speed_samples: 1,
+        speed_warmup: false,
+        retry_unstable: false,
+        latency_under_load: false,
+        load_test: &[],
+        latency_concurrency: 1,
+    };
+}
+
+impl Profile {
+    fn preset(self) -> Preset {
+        match self {
+            Profile::Fast => Preset {
+                latency_count: 5,
+                latency_concurrency: 4,
+                ..Preset::DEFAULT
+            },
+            Profile::Accurate => Preset {
+                latency_count: 20,
+                download_sizes: &[10 << 20],
+                speed_samples: 3,
+                speed_warmup: true,
+                retry_unstable: true,
+                ..Preset::DEFAULT
+            },
+            Profile::Thorough => Preset {
+                latency_count: 30,
+                download_sizes: &[1 << 20, 10 << 20, 50 << 20],
+                speed_samples: 3,
+                speed_warmup: true,
+                retry_unstable: true,
+                latency_under_load: true,
+                load_test: &[1, 4],
+                ..Preset::DEFAULT
+            },
+        }
+    }
+}
+
+/// The explicit value of a `--flag` / `--no-flag` pair, if either was given
+fn flag_override(on: bool, off: bool) -> Option<bool> {
+    (on || off).then_some(on)
+}
+
+/// Loss above which `--select stable-fast` ranks a node behind all stable ones
+const STABLE_FAST_MAX_LOSS: f64 = 0.2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum TableStyle {
+    Ascii,
+    Unicode,
+    Minimal,
+}
+
+/// Border characters of a bordered table style
+struct Borders {
+    horizontal: char,
+    vertical: char,
+    // 左 / 中 / 右 交叉点，依次为顶行、表头下方、底行
+    top: [char; 3],
+    middle: [char; 3],
+    bottom: [char; 3],
+}
+
+impl TableStyle {
+    fn borders(self) -> Option<Borders> {
+        match self {
+            TableStyle::Ascii => Some(Borders {
+                horizontal: '-',
+                vertical: '|',
+                top: ['+', '+', '+'],
+                middle: ['+', '+', '+'],
+                bottom: ['+', '+', '+'],
+            }),
+            TableStyle::Unicode => Some(Borders {
+                horizontal: '─',
+                vertical: '│',
+                top: ['┌', '┬', '┐'],
+                middle: ['├', '┼', '┤'],
+                bottom: ['└', '┴', '┘'],
+            }),
+            TableStyle::Minimal => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Cell {
+    text: String,
+    color: Option<&'static str>, // ANSI color code, applied after padding
+}
+
+impl Cell {
+    fn colored(self, color: &'static str) -> Self {
+        Cell {
+            color: Some(color),
+            ..self
+        }
+    }
+}
+
+impl<T: Into<String>> From<T> for Cell {
+    fn from(text: T) -> Self {
+        // 先替换符号再计算宽度，否则 --no-emoji 输出时列会错位
+        Cell {
+            text: console_text(text.into()),
+            color: None,
+        }
+    }
+}
+
+/// Left-align `text` to `width` terminal columns; `{:<width$}` counts chars instead,
+/// which misaligns CJK text
+fn pad_end(text: &str, width: usize) -> String {
+    format!("{text}{}", " ".repeat(width.saturating_sub(text.width())))
+}
+
+/// Right-aligned counterpart of `pad_end`
+fn pad_start(text: &str, width: usize) -> String {
+    format!("{}{text}", " ".repeat(width.saturating_sub(text.width())))
+}
+
+/// `text` without the ANSI color codes the console table may carry
+fn strip_ansi(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // 颜色码形如 ESC [ 数字;数字 m，跳到 m 为止
+            chars.by_ref().find(|&c| c == 'm');
+        } else {
+            plain.push(c);
+        }
+    }
+    plain
+}
+
+/// Column-aligned text table measuring display width, so CJK and emoji tags line up
+#[derive(Debug, Default)]
+struct TextTable {
+    headers: Vec<String>,
+    rows: Vec<Vec<Cell>>,
+}
+
+impl TextTable {
+    fn new() -> Self {
+        Self::default()
+    }
+
+    fn header(&mut self, header: impl Into<String>) {
+        self.headers.push(console_text(header.into()));
+    }
+
+    fn row(&mut self, row: Vec<Cell>) {
+        self.rows.push(row);
+    }
+
+    fn render(&self, style: TableStyle) -> String {
+        let mut widths: Vec<usize> = self.headers.iter().map(|h| h.width()).collect();
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.text.width());
+            }
+        }
+
+        let fmt_row = |cells: Vec<Cell>, borders: Option<&Borders>| {
+            let padded: Vec<String> = cells
+                .into_iter()
+                .zip(&widths)
+                .map(|(cell, &width)| {
+                    let padding = " ".repeat(width - cell.text.width());
+                    match cell.color {
+                        Some(color) => format!("\x1b[{}m{}\x1b[0m{}", color, cell.text, padding),
+                        None => format!("{}{}", cell.text, padding),
+                    }
+                })
+                .collect();
+            match borders {
+                Some(b) => {
+                    let sep = format!(" {} ", b.vertical);
+                    format!("{} {} {}\n", b.vertical, padded.join(&sep), b.vertical)
+                }
+                None => format!("{}\n", padded.join("  ").trim_end()),
+            }
+        };
+        let rule = |horizontal: char, [left, mid, right]: [char; 3]| {
+            let segments: Vec<String> = widths
+                .iter()
+                .map(|&w| horizontal.to_string().repeat(w + 2))
+                .collect();
+            format!("{}{}{}\n", left, segments.join(&mid.to_string()), right)
+        };
+
+        let header_cells = self.headers.iter().map(Cell::from).collect();
+        let mut row_cells = self.rows.iter().map(|row| {
+            let mut row = row.clone();
+            row.resize_with(widths.len(), Cell::default);
+            row
+        });
+
+        let mut out = String::new();
+        match style.borders() {
+            Some(b) => {
+                out += &rule(b.horizontal, b.top);
+                out += &fmt_row(header_cells, Some(&b));
+                out += &rule(b.horizontal, b.middle);
+                for row in row_cells.by_ref() {
+                    out += &fmt_row(row, Some(&b));
+                }
+                out += &rule(b.horizontal, b.bottom);
+            }
+            None => {
+                let total = widths.iter().sum::<usize>() + 2 * widths.len().saturating_sub(1);
+                out += &fmt_row(header_cells, None);
+                out += &format!("{}\n", "-".repeat(total));
+                for row in row_cells {
+                    out += &fmt_row(row, None);
+                }
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum OutputFormat {
+    Table,
+    TableCompact,
+    Json,
+    Csv,
+    Ranking,
+    Ndjson,
+}
+
+impl OutputFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Table => "txt",
+            OutputFormat::TableCompact => "compact.txt",
+            OutputFormat::Json => "json",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Ranking => "ranking.json",
+            OutputFormat::Ndjson => "ndjson",
+        }
+    }
+
+    /// Whether the format is shown on the console instead of written to stdout or a file
+    fn is_console(self) -> bool {
+        matches!(self, OutputFormat::Table | OutputFormat::TableCompact)
+    }
+
+    /// Render `(rank, result)` rows in the given order; `None` for the tables, which
+    /// are rendered along with the console output, and the streamed ndjson
+    fn render(self, rows: &[(usize, &NodeResult)], columns: &[Column]) -> Option<String> {
+        match self {
+            OutputFormat::Table | OutputFormat::TableCompact | OutputFormat::Ndjson => None,
+            OutputFormat::Json => Some(render_json(rows)),
+            OutputFormat::Csv => Some(render_csv(rows, columns)),
+            OutputFormat::Ranking => {
+                let mut rows = rows.to_vec();
+                ExportSort::Rank.apply(&mut rows);
+                let tags: Vec<&str> = rows.iter().map(|(_, r)| r.tag.as_str()).collect();
+                Some(serde_json::to_string(&tags).expect("tags are always serializable"))
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RankedResult<'a> {
+    rank: usize,
+    #[serde(flatten)]
+    result: &'a NodeResult,
+}
+
+fn render_json(rows: &[(usize, &NodeResult)]) -> String {
+    let ranked: Vec<_> = rows
+        .iter()
+        .map(|&(rank, result)| RankedResult { rank, result })
+        .collect();
+    serde_json::to_string_pretty(&ranked).expect("results are always serializable")
+}
+
+/// A column of the result table and CSV export, chosen with `--columns`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Column {
+    Grade,
+    Rank,
+    Status,
+    Port,
+    Median,
+    Average,
+    Minimum,
+    Maximum,
+    Trim,
+    Ci,
+    Speed,
+    Peak,
+    ExitIp,
+    Country,
+    Dns,
+    Ipv6,
+    Http,
+    Tls,
+    Health,
+    Baseline,
+    Tag,
+}
+
+impl Column {
+    /// CSV columns when `--columns` is not given
+    const CSV_DEFAULT: [Column; 11] = [
+        Column::Rank,
+        Column::Tag,
+        Column::Port,
+        Column::Status,
+        Column::Median,
+        Column::Average,
+        Column::Minimum,
+        Column::Maximum,
+        Column::Speed,
+        Column::Trim,
+        Column::Peak,
+    ];
+
+    /// CSV header fields of the column; empty for table-only columns
+    fn csv_fields(self) -> &'static [&'static str] {
+        match self {
+            Column::Rank => &["rank"],
+            Column::Tag => &["tag"],
+            Column::Port => &["port"],
+            Column::Status => &["latency_status"],
+            Column::Median => &["median"],
+            Column::Average => &["average"],
+            Column::Minimum => &["minimum"],
+            Column::Maximum => &["maximum"],
+            Column::Speed => &["speed_mbps", "speed_error"],
+            Column::Trim => &["trimmed_mean"],
+            Column::Ci => &["ci95"],
+            Column::Peak => &["peak_mbps"],
+            Column::Ipv6 => &["ipv6_ok"],
+            _ => &[],
+        }
+    }
+}
+
+fn render_csv(rows: &[(usize, &NodeResult)], columns: &[Column]) -> String {
+    fn quote(field: &str) -> String {
+        if field.contains([',', '"', '\n']) {
+            format!("\"{}\"", field.replace('"', "\"\""))
+        } else {
+            field.to_string()
+        }
+    }
+
+    let columns = if columns.is_empty() {
+        &Column::CSV_DEFAULT[..]
+    } else {
+        columns
+    };
+    let header: Vec<&str> = columns
+        .iter()
+        .flat_map(|column| column.csv_fields())
+        .copied()
+        .collect();
+    let mut csv = header.join(",") + "\n";
+    for &(rank, result) in rows {
+        let (status, stats) = match &result.latency {
+            LatencyResult::Success {
+                median,
+                average,
+                minimum,
+                maximum,
+                ..
+            } => (
+                "success".to_string(),
+                [median, average, minimum, maximum].map(|value| format!("{value:.2}")),
+            ),
+            other => (other.to_string(), Default::default()),
+        };
+        let (speed, speed_error) = match &result.speed {
+            Some(SpeedResult::Success(mbps)) => (format!("{mbps:.2}"), String::new()),
+            Some(failed) => (String::new(), failed.to_string()),
+            None => (String::new(), String::new()),
+        };
+        let trimmed_mean = match &result.latency {
+            LatencyResult::Success {
+                trimmed_mean: Some(trimmed),
+                ..
+            } => format!("{trimmed:.2}"),
+            _ => String::new(),
+        };
+        let ci = match &result.latency {
+            LatencyResult::Success { ci: Some(ci), .. } => format!("{ci:.2}"),
+            _ => String::new(),
+        };
+        let fields: Vec<String> = columns
+            .iter()
+            .flat_map(|column| match column {
+                Column::Rank => vec![rank.to_string()],
+                Column::Tag => vec![quote(&result.tag)],
+                Column::Port => vec![result.port.to_string()],
+                Column::Status => vec![quote(&status)],
+                Column::Median => vec![stats[0].clone()],
+                Column::Average => vec![stats[1].clone()],
+                Column::Minimum => vec![stats[2].clone()],
+                Column::Maximum => vec![stats[3].clone()],
+                Column::Speed => vec![speed.clone(), quote(&speed_error)],
+                Column::Trim => vec![trimmed_mean.clone()],
+                Column::Ci => vec![ci.clone()],
+                Column::Peak => vec![result
+                    .peak_speed
+                    .map_or_else(String::new, |mbps| format!("{mbps:.2}"))],
+                Column::Ipv6 => vec![result.ipv6_ok.map_or_else(String::new, |ok| ok.to_string())],
+                _ => Vec::new(),
+            })
+            .collect();
+        csv += &fields.join(",");
+        csv += "\n";
+    }
+    csv
+}
+
+/// A byte count parsed from strings like `512B`, `500KB` or `1.5GB`; bare numbers are MB
+#[derive(Debug, Clone, Copy)]
+struct ByteSize(u64);
+
+impl std::str::FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let number: f64 = number
+            .parse()
+            .map_err(|_| format!("invalid size: {:?}", s))?;
+        let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+            // 无单位时按 MB 处理，与原先 `-d <MB>` 的含义保持一致
+            "" | "m" | "mb" | "mib" => 1 << 20,
+            "b" => 1u64,
+            "k" | "kb" | "kib" => 1 << 10,
+            "g" | "gb" | "gib" => 1 << 30,
+            "t" | "tb" | "tib" => 1 << 40,
+            _ => return Err(format!("unknown size unit: {:?}", unit)),
+        };
+        let bytes = (number * multiplier as f64).round();
+        if !(bytes >= 1.0 && bytes < u64::MAX as f64) {
+            return Err(format!("size out of range: {:?}", s));
+        }
+        Ok(ByteSize(bytes as u64))
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if value.fract() == 0.0 {
+        format!("{} {}", value, UNITS[unit])
+    } else {
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
+
+/// `host:port` target for the tcp-ping probe; IPv6 literals go in brackets
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ProbeHost {
+    host: String,
+    port: u16,
+}
+
+impl std::str::FromStr for ProbeHost {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("expected host:port, got {:?}", s))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() || host.len() > 255 {
+            return Err(format!("invalid host: {:?}", host));
+        }
+        let port = port
+            .parse()
+            .map_err(|_| format!("invalid port: {:?}", port))?;
+        Ok(ProbeHost {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl std::fmt::Display for ProbeHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum SpeedUnit {
+    #[default]
+    Mbps,
+    Mibps,
+    Mbytes,
+}
+
+/// Chosen once in `main`, read by the `Display` impls
+static SPEED_UNIT: OnceLock<SpeedUnit> = OnceLock::new();
+
+/// Decimal places for latencies and speeds in human-readable output, set by `--precision`
+static PRECISION: OnceLock<usize> = OnceLock::new();
+
+fn precision() -> usize {
+    PRECISION.get().copied().unwrap_or(2)
+}
+
+/// Decimal and thousands separators of a `--locale`
+#[derive(Debug, Clone, Copy, Serialize)]
+struct NumberLocale {
+    decimal: char,
+    group: char,
+}
+
+/// Set by `--locale`; without it numbers keep Rust's `1234.56` form
+static NUMBER_LOCALE: OnceLock<NumberLocale> = OnceLock::new();
+
+impl std::str::FromStr for NumberLocale {
+    type Err = String;
+
+    /// Accepts tags like `de`, `de-DE` or `de_DE.UTF-8`; only the language matters
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let language = s
+            .split(['-', '_', '.'])
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let (decimal, group) = match language.as_str() {
+            "en" | "zh" | "ja" | "ko" | "th" | "he" | "c" | "posix" => ('.', ','),
+            "de" | "es" | "it" | "nl" | "pt" | "id" | "tr" | "da" | "el" | "ro" | "vi" => {
+                (',', '.')
+            }
+            // 这些语言用空格分组，用不换行空格避免表格内换行
+            "fr" | "ru" | "pl" | "cs" | "sk" | "sv" | "fi" | "nb" | "no" | "uk" | "hu" => {
+                (',', '\u{A0}')
+            }
+            _ => return Err(format!("unsupported locale: {:?}", s)),
+        };
+        Ok(NumberLocale { decimal, group })
+    }
+}
+
+/// Apply the `--locale` separators to a number already formatted by Rust
+fn localize_number(formatted: String) -> String {
+    match NUMBER_LOCALE.get() {
+        Some(locale) => locale.apply(formatted),
+        None => formatted,
+    }
+}
+
+impl NumberLocale {
+    fn apply(self, formatted: String) -> String {
+        let (sign, unsigned) = match formatted.strip_prefix(['+', '-']) {
+            Some(rest) => formatted.split_at(formatted.len() - rest.len()),
+            None => ("", formatted.as_str()),
+        };
+        let (integer, fraction) = match unsigned.split_once('.') {
+            Some((integer, fraction)) => (integer, Some(fraction)),
+            None => (unsigned, None),
+        };
+        // inf、NaN 之类不是数字，原样返回
+        if !integer.bytes().all(|b| b.is_ascii_digit()) {
+            return formatted;
+        }
+        let mut localized = sign.to_string();
+        for (idx, digit) in integer.chars().enumerate() {
+            if idx > 0 && (integer.len() - idx).is_multiple_of(3) {
+                localized.push(self.group);
+            }
+            localized.push(digit);
+        }
+        if let Some(fraction) = fraction {
+            localized.push(self.decimal);
+            localized += fraction;
+        }
+        localized
+    }
+}
+
+/// `value` with `--precision` decimals, in the `--locale` number format
+fn decimal(value: f64) -> String {
+    localize_number(format!("{value:.*}", precision()))
+}
+
+/// Like `decimal`, always with a sign
+fn signed_decimal(value: f64) -> String {
+    localize_number(format!("{value:+.*}", precision()))
+}
+
+impl SpeedUnit {
+    fn current() -> Self {
+        SPEED_UNIT.get().copied().unwrap_or_default()
+    }
+
+    /// Convert from the canonical Mbps value stored in `SpeedResult`
+    fn convert(self, mbps: f64) -> f64 {
+        match self {
+            SpeedUnit::Mbps => mbps,
+            SpeedUnit::Mibps => mbps * 1_000_000.0 / 8.0 / 1024.0 / 1024.0,
+            SpeedUnit::Mbytes => mbps / 8.0,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            SpeedUnit::Mbps => "Mbps",
+            SpeedUnit::Mibps => "MiB/s",
+            SpeedUnit::Mbytes => "MB/s",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct StatusRanges(Vec<(u16, u16)>);
+
+impl StatusRanges {
+    fn contains(&self, status: reqwest::StatusCode) -> bool {
+        let code = status.as_u16();
+        self.0.iter().any(|&(lo, hi)| lo <= code && code <= hi)
+    }
+
+    /// Whether any 3xx code is accepted, in which case redirects must not be followed
+    fn accepts_redirects(&self) -> bool {
+        self.0.iter().any(|&(lo, hi)| lo <= 399 && hi >= 300)
+    }
+}
+
+impl std::fmt::Display for StatusRanges {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, &(lo, hi)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            if lo == hi {
+                write!(f, "{lo}")?;
+            } else {
+                write!(f, "{lo}-{hi}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for StatusRanges {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_code = |code: &str| {
+            code.trim()
+                .parse::<u16>()
+                .ok()
+                .filter(|code| (100..=999).contains(code))
+                .ok_or_else(|| format!("invalid HTTP status code: {:?}", code))
+        };
+
+        let mut ranges = Vec::new();
+        for part in s.split(',').filter(|part| !part.trim().is_empty()) {
+            let range = match part.split_once('-') {
+                Some((lo, hi)) => (parse_code(lo)?, parse_code(hi)?),
+                None => {
+                    let code = parse_code(part)?;
+                    (code, code)
+                }
+            };
+            if range.0 > range.1 {
+                return Err(format!("invalid status range: {:?}", part));
+            }
+            ranges.push(range);
+        }
+
+        if ranges.is_empty() {
+            return Err("no status codes given".to_string());
+        }
+        Ok(StatusRanges(ranges))
+    }
+}
+
+/// Environment variable consulted when no config path is given on the command line
+const CONFIG_PATH_ENV: &str = "PROXY_SPEEDTEST_CONFIG";
+
+#[derive(Debug, Deserialize)]
+struct Config {
+    inbounds: Option<Vec<Inbound>>,
+    experimental: Option<Experimental>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Experimental {
+    clash_api: Option<ClashApiConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClashApiConfig {
+    secret: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Inbound {
+    #[serde(rename = "type")]
+    inbound_type: Option<String>,
+    tag: Option<String>,
+    listen_port: Option<u16>,
+    listen: Option<String>,
+    #[serde(default)]
+    users: Vec<InboundUser>,
+    /// Everything else, for annotations like `NODE_TARGET_FIELD`
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Inbound field read by `--respect-node-target`; not a SingBox field
+const NODE_TARGET_FIELD: &str = "speedtest_url";
+
+/// The inbound's `NODE_TARGET_FIELD`, if it is a URL `--latency-url` would accept
+fn inbound_target(
+    tag: &str,
+    extra: &serde_json::Map<String, serde_json::Value>,
+    allow_http: bool,
+) -> Option<String> {
+    let target = extra.get(NODE_TARGET_FIELD)?;
+    // 与 --latency-url 同样的规则：默认只接受 https
+    let url = target.as_str().filter(|url| match url.split_once("://") {
+        Some(("https", _)) => true,
+        Some(("http", _)) => allow_http,
+        _ => false,
+    });
+    if url.is_none() {
+        errln!(
+            "⚠️  {} 的 {} 不是可用的 https:// 地址，改用 --latency-url",
+            tag,
+            NODE_TARGET_FIELD
+        );
+    }
+    url.map(str::to_string)
+}
+
+/// Credentials declared by a `socks`, `http` or `mixed` inbound
+#[derive(Debug, Clone, Deserialize)]
+struct InboundUser {
+    username: String,
+    #[serde(default)]
+    password: String,
+}
+
+const KNOWN_TOP_LEVEL_FIELDS: &[&str] = &[
+    "$schema",
+    "log",
+    "dns",
+    "ntp",
+    "certificate",
+    "endpoints",
+    "inbounds",
+    "outbounds",
+    "route",
+    "services",
+    "experimental",
+];
+
+/// Listen fields shared by every SingBox inbound
+const KNOWN_LISTEN_FIELDS: &[&str] = &[
+    "type",
+    "tag",
+    "listen",
+    "listen_port",
+    "bind_interface",
+    "routing_mark",
+    "reuse_addr",
+    "netns",
+    "tcp_fast_open",
+    "tcp_multi_path",
+    "disable_tcp_keep_alive",
+    "tcp_keep_alive",
+    "tcp_keep_alive_interval",
+    "udp_fragment",
+    "udp_timeout",
+    "detour",
+    "sniff",
+    "sniff_override_destination",
+    "sniff_timeout",
+    "domain_strategy",
+    "udp_disable_domain_unmapping",
+];
+
+/// Type-specific fields of the proxy inbounds this tool understands
+fn known_inbound_fields(inbound_type: &str) -> Option<&'static [&'static str]> {
+    match inbound_type {
+        "socks" => Some(&["users"]),
+        "http" => Some(&["users", "tls", "set_system_proxy"]),
+        "mixed" => Some(&["users", "set_system_proxy"]),
+        _ => None,
+    }
+}
+
+/// Whether an inbound's `listen` address can be reached through 127.0.0.1
+///
+/// Wildcard binds (`0.0.0.0`, `::`) accept loopback connections too
+fn is_loopback_listen(listen: &str) -> bool {
+    matches!(listen, "127.0.0.1" | "::1" | "localhost")
+        || listen
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_unspecified())
+}
+
+/// Collect every unexpected key as a path like `inbounds[2].listen_prot`
+fn find_unknown_fields(config: &serde_json::Value) -> Vec<String> {
+    let mut unknown = Vec::new();
+    let Some(root) = config.as_object() else {
+        return unknown;
+    };
+
+    for key in root.keys() {
+        if !KNOWN_TOP_LEVEL_FIELDS.contains(&key.as_str()) {
+            unknown.push(key.clone());
+        }
+    }
+
+    let inbounds = root.get("inbounds").and_then(|v| v.as_array());
+    for (idx, inbound) in inbounds.into_iter().flatten().enumerate() {
+        let Some(inbound) = inbound.as_object() else {
+            continue;
+        };
+        let type_fields = inbound
+            .get("type")
+            .and_then(|v| v.as_str())
+            .and_then(known_inbound_fields);
+        // 其它类型的字段集未知，无法可靠检查
+        let Some(type_fields) = type_fields else {
+            continue;
+        };
+        for key in inbound.keys() {
+            if !KNOWN_LISTEN_FIELDS.contains(&key.as_str())
+                && !type_fields.contains(&key.as_str())
+                && key != NODE_TARGET_FIELD
+            {
+                unknown.push(format!("inbounds[{}].{}", idx, key));
+            }
+        }
+    }
+
+    unknown
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum LatencyResult {
+    Success {
+        median: f64,
+        average: f64,
+        minimum: f64,
+        maximum: f64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        trimmed_mean: Option<f64>, // only with `--robust`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ci: Option<f64>, // half-width of the 95% interval for the mean, only with `--ci`
+    },
+    Unstable(usize, usize), // valid_count, total_count
+    AllFailed,
+    SessionError(String),
+    TlsError(String), // certificate or handshake rejected, e.g. the node intercepts TLS
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum SpeedResult {
+    Success(f64), // Speed in Mbps
+    TooSlow(f64), // completed, but below `--speed-floor`
+    Failed { reason: FailReason, detail: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+enum FailReason {
+    Session, // proxy/client construction or invalid parameters
+    Connect, // request never got a response
+    Timeout,
+    Tls,    // certificate or handshake rejected
+    Http,   // got a response with a non-success status
+    Stream, // response started but the body broke mid-way
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct NodeResult {
+    tag: String,
+    port: u16,
+    latency: LatencyResult,
+    #[serde(default)]
+    samples: Vec<Option<f64>>, // per-attempt latency in ms, `None` for a failed attempt
+    speed: Option<SpeedResult>,
+    #[serde(default)]
+    loaded_latency: Option<LatencyResult>, // measured while a download saturates the node
+    #[serde(default)]
+    latency_failure: Option<FailReason>, // why the last failed latency attempt failed
+    #[serde(default)]
+    exit_ip: Option<String>,
+    #[serde(default)]
+    country: Option<String>, // `loc` from the trace, e.g. `US`
+    #[serde(default)]
+    leak_suspected: bool, // exit IP equals the direct IP
+    #[serde(default)]
+    dns_ms: Option<f64>, // proxy-side name resolution time, set by `--dns-probe`
+    #[serde(default)]
+    ipv6_ok: Option<bool>, // set by `--check-ipv6`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    speed_samples: Vec<f64>, // Mbps of each successful download, set by `--speed-samples`
+    #[serde(default)]
+    http_error: Option<u16>, // last rejected status timed by `--measure-error-latency`
+    #[serde(default)]
+    tls: Option<TlsCheck>, // set by `--show-tls`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    sweep: Vec<SweepPoint>, // one entry per `-d` size when several are given
+    #[serde(default)]
+    peak_speed: Option<f64>, // Mbps over the best `PEAK_WINDOW` of the download
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SweepPoint {
+    bytes: u64,
+    speed: SpeedResult,
+}
+
+/// Outcome of `--show-tls`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum TlsCheck {
+    Negotiated { version: String, cipher: String },
+    Failed(String), // includes certificate verification errors
+}
+
+impl std::fmt::Display for TlsCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsCheck::Negotiated { version, cipher } => write!(f, "{} {}", version, cipher),
+            TlsCheck::Failed(err) => write!(f, "Failed: {}", err),
+        }
+    }
+}
+
+/// A node's record in `--health-file`: exponentially weighted moving averages
+/// over all runs that tested it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct NodeHealth {
+    success_rate: f64,    // 0..=1, a run counts as success if latency succeeded
+    latency: Option<f64>, // median latency in ms, over successful runs only
+    runs: u32,
+}
+
+impl NodeHealth {
+    /// Fold in one run's result with weight `alpha`; the first run is taken as is
+    fn update(&mut self, result: &NodeResult, alpha: f64) {
+        let ewma = |old: f64, new: f64| alpha * new + (1.0 - alpha) * old;
+        let success = match result.latency {
+            LatencyResult::Success { median, .. } => {
+                self.latency = Some(match self.latency {
+                    Some(old) => ewma(old, median),
+                    None => median,
+                });
+                1.0
+            }
+            _ => 0.0,
+        };
+        self.success_rate = if self.runs == 0 {
+            success
+        } else {
+            ewma(self.success_rate, success)
+        };
+        self.runs += 1;
+    }
+}
+
+impl std::fmt::Display for NodeHealth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.0}%", self.success_rate * 100.0)?;
+        if let Some(latency) = self.latency {
+            write!(f, " {} ms", decimal(latency))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LoadLevelResult {
+    concurrency: usize,
+    probes_ok: usize,
+    probes_total: usize,
+    median_latency: Option<f64>,
+    aggregate_speed: Option<SpeedResult>, // summed over all parallel downloads
+}
+
+impl NodeResult {
+    /// Result of a node that could not be tested at all
+    fn failed(tag: &str, port: u16, latency: LatencyResult) -> Self {
+        NodeResult {
+            tag: tag.to_string(),
+            port,
+            latency,
+            samples: Vec::new(),
+            speed: None,
+            loaded_latency: None,
+            latency_failure: None,
+            exit_ip: None,
+            country: None,
+            leak_suspected: false,
+            dns_ms: None,
+            ipv6_ok: None,
+            speed_samples: Vec::new(),
+            http_error: None,
+            tls: None,
+            sweep: Vec::new(),
+            peak_speed: None,
+        }
+    }
+
+    /// One-line, unaligned summary used for streamed output
+    fn summary_line(&self) -> String {
+        let with_unit = |latency: &LatencyResult| match latency {
+            LatencyResult::Success { .. } => format!("{} ms", latency),
+            other => other.to_string(),
+        };
+        let mut line = format!(
+            "{} (端口 {}) | 延迟 {}",
+            self.tag,
+            self.port,
+            with_unit(&self.latency)
+        );
+        if let Some(loaded) = &self.loaded_latency {
+            line += &format!(" | 负载下延迟 {}", with_unit(loaded));
+        }
+        if let Some(speed) = &self.speed {
+            line += &format!(" | 速度 {}", speed);
+        }
+        if let Some(peak) = self.peak_speed {
+            line += &format!(" | 峰值 {}", SpeedResult::Success(peak));
+        }
+        line
+    }
+
+    /// `#rank median speed [tag]` line for `--format table-compact`; a failed
+    /// measurement shows its status instead of a number
+    fn compact_line(&self, rank: usize) -> String {
+        let mut line = format!("#{} ", rank);
+        match &self.latency {
+            LatencyResult::Success { median, .. } => line += &format!("{}ms", decimal(*median)),
+            _ => line += self.status_label(),
+        }
+        if let Some(speed) = &self.speed {
+            line += " ";
+            match speed {
+                SpeedResult::Success(speed) => {
+                    let unit = SpeedUnit::current();
+                    line += &format!("{}{}", decimal(unit.convert(*speed)), unit.label());
+                }
+                SpeedResult::TooSlow(_) => line += "SLOW",
+                SpeedResult::Failed { reason, .. } => line += &reason.to_string(),
+            }
+        }
+        line + &format!(" [{}]", self.tag)
+    }
+
+    /// Fraction of the planned latency attempts that did not succeed
+    fn loss_ratio(&self, test_count: usize) -> f64 {
+        // 旧断点没有 samples，只能按结果粗略判断
+        if self.samples.is_empty() {
+            return match self.latency {
+                LatencyResult::Success { .. } => 0.0,
+                _ => 1.0,
+            };
+        }
+        let ok = self.samples.iter().flatten().count();
+        1.0 - ok as f64 / test_count.max(1) as f64
+    }
+
+    /// Cause listed in the summary's failure breakdown: the outer `None` for a node
+    /// that didn't fail, the inner one when the cause wasn't recorded
+    fn failure_reason(&self) -> Option<Option<FailReason>> {
+        match (&self.latency, &self.speed) {
+            (LatencyResult::SessionError(_), _) => Some(Some(FailReason::Session)),
+            (LatencyResult::TlsError(_), _) => Some(Some(FailReason::Tls)),
+            (LatencyResult::AllFailed, _) => Some(self.latency_failure),
+            (_, Some(SpeedResult::Failed { reason, .. })) => Some(Some(*reason)),
+            _ => None,
+        }
+    }
+
+    /// Compact state for the table's `STATUS` column
+    fn status_label(&self) -> &'static str {
+        match (&self.latency, &self.speed) {
+            (LatencyResult::Unstable(..), _) => "UNSTABLE",
+            (LatencyResult::AllFailed, _) => "FAILED",
+            (LatencyResult::SessionError(_), _) => "ERROR",
+            (LatencyResult::TlsError(_), _) => "TLS",
+            (LatencyResult::Success { .. }, Some(SpeedResult::TooSlow(_))) => "SLOW",
+            (LatencyResult::Success { .. }, Some(SpeedResult::Failed { .. })) => "FAILED",
+            (LatencyResult::Success { .. }, _) => "OK",
+        }
+    }
+
+    /// How bad the node's problem is, 0 for a healthy result; used by `--failures-first`
+    fn severity(&self) -> u8 {
+        match (&self.latency, &self.speed) {
+            // 延迟全部失败比只有下载失败更严重
+            (LatencyResult::AllFailed | LatencyResult::SessionError(_), _) => 5,
+            (LatencyResult::TlsError(_), _) => 4,
+            (LatencyResult::Success { .. }, Some(SpeedResult::Failed { .. })) => 3,
+            (LatencyResult::Unstable(..), _) => 2,
+            (LatencyResult::Success { .. }, Some(SpeedResult::TooSlow(_))) => 1,
+            (LatencyResult::Success { .. }, _) => 0,
+        }
+    }
+
+    /// Change of median latency and download speed in percent, relative to `old`
+    fn change_from(&self, old: &NodeResult) -> (Option<f64>, Option<f64>) {
+        let percent = |old: f64, new: f64| (old > 0.0).then(|| (new - old) / old * 100.0);
+        let latency = match (&old.latency, &self.latency) {
+            (LatencyResult::Success { median: was, .. }, LatencyResult::Success { median, .. }) => {
+                percent(*was, *median)
+            }
+            _ => None,
+        };
+        let speed = match (&old.speed, &self.speed) {
+            (Some(SpeedResult::Success(was)), Some(SpeedResult::Success(speed))) => {
+                percent(*was, *speed)
+            }
+            _ => None,
+        };
+        (latency, speed)
+    }
+
+    /// Median latency increase under load, if both measurements succeeded
+    fn bufferbloat(&self) -> Option<(f64, f64)> {
+        match (&self.latency, self.loaded_latency.as_ref()?) {
+            (
+                LatencyResult::Success { median: idle, .. },
+                LatencyResult::Success { median: busy, .. },
+            ) => Some((*idle, *busy)),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for LatencyResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LatencyResult::Success {
+                median,
+                average,
+                maximum,
+                minimum,
+                ..
+            } => {
+                write!(
+                    f,
+                    "{}/{}/{}/{}",
+                    decimal(*median),
+                    decimal(*average),
+                    decimal(*minimum),
+                    decimal(*maximum)
+                )
+            }
+            LatencyResult::Unstable(valid, total) => write!(f, "Unstable ({}/{})", valid, total),
+            LatencyResult::AllFailed => write!(f, "All Failed"),
+            LatencyResult::SessionError(err) => write!(f, "Session Error: {}", err),
+            LatencyResult::TlsError(err) => write!(f, "TLS Error: {}", err),
+        }
+    }
+}
+
+impl std::fmt::Display for SpeedResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpeedResult::Success(speed) => {
+                let unit = SpeedUnit::current();
+                write!(f, "{} {}", decimal(unit.convert(*speed)), unit.label())
+            }
+            SpeedResult::TooSlow(speed) => {
+                let unit = SpeedUnit::current();
+                write!(
+                    f,
+                    "Too Slow ({} {})",
+                    decimal(unit.convert(*speed)),
+                    unit.label()
+                )
+            }
+            SpeedResult::Failed { reason, detail } => write!(f, "{}: {}", reason, detail),
+        }
+    }
+}
+
+impl std::fmt::Display for FailReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let label = match self {
+            FailReason::Session => "Session Error",
+            FailReason::Connect => "Connect Error",
+            FailReason::Timeout => "Timeout",
+            FailReason::Tls => "TLS Error",
+            FailReason::Http => "HTTP Error",
+            FailReason::Stream => "Stream Error",
+        };
+        f.write_str(label)
+    }
+}
+
+impl SpeedResult {
+    fn failed(reason: FailReason, detail: impl Into<String>) -> Self {
+        SpeedResult::Failed {
+            reason,
+            detail: detail.into(),
+        }
+    }
+}
+
+const LATENCY_URL: &str = "https://www.cloudflare.com/cdn-cgi/trace";
+
+fn download_url(bytes: u64) -> String {
+    format!("https://speed.cloudflare.com/__down?bytes={}", bytes)
+}
+
+/// Where a trace request goes: through a node's socks port, or direct from a local address
+enum TraceRoute {
+    Node(u16),
+    Direct(Option<std::net::IpAddr>),
+}
+
+/// Fetch the Cloudflare trace page, directly or through a node, as `key=value` pairs
+async fn fetch_trace(route: TraceRoute) -> Result<BTreeMap<String, String>, String> {
+    let mut client = test_client_builder()
+        .timeout(Duration::from_secs(10))
+        .connect_timeout(Duration::from_secs(5));
+    match route {
+        TraceRoute::Node(port) => {
+            let proxy = Proxy::all(proxy_url(port))
+                .map_err(|e| format!("Failed to create proxy: {}", e))?;
+            client = client.proxy(proxy);
+        }
+        TraceRoute::Direct(local_address) => {
+            client = client.no_proxy().local_address(local_address);
+        }
+    }
+    let client = client
+        .build()
+        .map_err(|e| format!("Failed to create client: {}", e))?;
+
+    let response = client
+        .get(LATENCY_URL)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("HTTP Error {}", response.status()));
+    }
+    let body = response.text().await.map_err(|e| e.to_string())?;
+    Ok(parse_trace(&body))
+}
+
+/// The same Cloudflare endpoint by hostname and by IP literal, for `--dns-probe`
+const DNS_PROBE_HOST_URL: &str = "https://one.one.one.one/cdn-cgi/trace";
+const DNS_PROBE_IP_URL: &str = "https://1.1.1.1/cdn-cgi/trace";
+
+/// Estimate proxy-side DNS time as the extra cost of a hostname over an IP literal
+///
+/// Both requests use a fresh client so each pays the same TCP and TLS setup, and
+/// the hostname goes first so the proxy's resolver cache is cold
+async fn test_node_dns(port: u16) -> Result<f64, String> {
+    let timed = |url: &'static str| async move {
+        let proxy =
+            Proxy::all(proxy_url(port)).map_err(|e| format!("Failed to create proxy: {}", e))?;
+        let client = test_client_builder()
+            .proxy(proxy)
+            .timeout(Duration::from_secs(10))
+            .build()
+            .map_err(|e| format!("Failed to create client: {}", e))?;
+        let start = Instant::now();
+        client.head(url).send().await.map_err(|e| e.to_string())?;
+        Ok::<_, String>(start.elapsed().as_micros() as f64 / 1000.0)
+    };
+    let by_name = timed(DNS_PROBE_HOST_URL).await?;
+    let by_ip = timed(DNS_PROBE_IP_URL).await?;
+    Ok((by_name - by_ip).max(0.0))
+}
+
+/// Cloudflare's resolver by IPv6 literal, so only a node with IPv6 egress can reach it
+const IPV6_PROBE_URL: &str = "https://[2606:4700:4700::1111]/cdn-cgi/trace";
+
+/// Whether the node can fetch `IPV6_PROBE_URL`, for `--check-ipv6`; only meaningful
+/// for a node that already answered the latency probes
+async fn test_node_ipv6(port: u16) -> Result<bool, String> {
+    let proxy =
+        Proxy::all(proxy_url(port)).map_err(|e| format!("Failed to create proxy: {}", e))?;
+    let client = test_client_builder()
+        .proxy(proxy)
+        .timeout(Duration::from_secs(10))
+        .build()
+        .map_err(|e| format!("Failed to create client: {}", e))?;
+    // 任何 HTTP 响应都说明 IPv6 连接已建立
+    Ok(client.head(IPV6_PROBE_URL).send().await.is_ok())
+}
+
+fn parse_trace(body: &str) -> BTreeMap<String, String> {
+    body.lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+/// Upper bound for the doubling done by `--min-download-time`
+const MAX_AUTO_DOWNLOAD_BYTES: u64 = 1 << 30;
+
+/// Size of the optional warmup download before the measured one
+const WARMUP_DOWNLOAD_BYTES: u64 = 1 << 20;
+
+/// Read the body chunk by chunk without buffering it, returning the byte count
+async fn drain_body(mut response: reqwest::Response) -> reqwest::Result<u64> {
+    let mut total = 0;
+    while let Some(chunk) = response.chunk().await? {
+        total += chunk.len() as u64;
+    }
+    Ok(total)
+}
+
+/// Shortest elapsed time used as a divisor; timers can read zero for tiny transfers
+const MIN_MEASURABLE: Duration = Duration::from_micros(1);
+
+/// Megabits per second for `bytes` transferred in `elapsed`, always finite
+fn throughput_mbps(bytes: u64, elapsed: Duration) -> f64 {
+    let megabits = bytes as f64 * 8.0 / 1_000_000.0;
+    megabits / elapsed.max(MIN_MEASURABLE).as_secs_f64()
+}
+
+/// Width of the rolling window for the peak sustained download speed
+const PEAK_WINDOW: Duration = Duration::from_secs(2);
+
+/// How long a download runs before `--abort-below-floor` may judge it
+const EARLY_ABORT_AFTER: Duration = Duration::from_secs(3);
+
+/// Like `drain_body`, also recording `(seconds since start, bytes so far)` after each chunk
+///
+/// With `abort_below` (Mbps), gives up once the download has run for `EARLY_ABORT_AFTER`
+/// at less than half that rate, so slow start alone can't trigger it; the last value
+/// tells whether it did
+async fn drain_body_sampled(
+    mut response: reqwest::Response,
+    start: Instant,
+    abort_below: Option<f64>,
+) -> reqwest::Result<(u64, Vec<(f64, u64)>, bool)> {
+    let mut total = 0;
+    let mut progress = vec![(0.0, 0)];
+    while let Some(chunk) = response.chunk().await? {
+        total += chunk.len() as u64;
+        let elapsed = start.elapsed();
+        progress.push((elapsed.as_secs_f64(), total));
+        if abort_below.is_some_and(|floor| {
+            elapsed >= EARLY_ABORT_AFTER && throughput_mbps(total, elapsed) < floor / 2.0
+        }) {
+            return Ok((total, progress, true));
+        }
+    }
+    Ok((total, progress, false))
+}
+
+/// Highest throughput in bytes/s over any `window` seconds of a download's progress
+///
+/// Leaves out TCP slow-start at the beginning of the transfer; `None` when the
+/// download finished before a whole window passed
+fn peak_window_rate(progress: &[(f64, u64)], window: f64) -> Option<f64> {
+    let mut peak: Option<f64> = None;
+    let mut from = 0;
+    for (to, &(end, bytes)) in progress.iter().enumerate() {
+        // 窗口起点取距离终点至少 window 秒的最后一个采样点
+        while from + 1 < to && end - progress[from + 1].0 >= window {
+            from += 1;
+        }
+        let (begin, bytes_before) = progress[from];
+        if end - begin >= window {
+            let rate = (bytes - bytes_before) as f64 / (end - begin);
+            peak = Some(peak.map_or(rate, |peak| peak.max(rate)));
+        }
+    }
+    peak
+}
+
+/// How long a `429 Too Many Requests` response asks us to wait before retrying
+///
+/// Only the delay-seconds form of `Retry-After` is understood; a missing or HTTP-date
+/// value falls back to one second, and the wait is capped at a minute
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    if response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    let seconds = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(1);
+    Some(Duration::from_secs(seconds.min(60)))
+}
+
+/// Outcome of a probe request sent under `tokio::time::timeout`
+type ProbeResponse = Result<Result<reqwest::Response, reqwest::Error>, tokio::time::error::Elapsed>;
+
+/// Pause before retrying a transient failure for `--retry-transient`
+const RETRY_PAUSE: Duration = Duration::from_secs(1);
+
+/// Which failed probe requests are sent once more
+#[derive(Debug, Clone, Copy, Default)]
+struct RetryPolicy {
+    rate_limited: bool,        // `--honor-retry-after`
+    transient: bool,           // `--retry-transient`
+    deadline: Option<Instant>, // `--node-budget`, a wait past it skips the retry
+}
+
+/// Whether a failed probe request is worth repeating: timeouts, reset connections and
+/// 429/502/503/504 often pass, while a bad proxy URL or a 400/404 would fail the same
+/// way again and only use up `--retry-budget`
+fn is_retryable(result: &ProbeResponse) -> bool {
+    match result {
+        Err(_) => true,
+        Ok(Ok(response)) => matches!(response.status().as_u16(), 429 | 502 | 503 | 504),
+        Ok(Err(error)) => error.is_timeout() || is_connection_reset(error),
+    }
+}
+
+/// Whether the connection was cut by the other side somewhere along the error chain
+fn is_connection_reset(error: &reqwest::Error) -> bool {
+    use std::io::ErrorKind;
+
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(err) = source {
+        if let Some(io) = err.downcast_ref::<std::io::Error>() {
+            if matches!(
+                io.kind(),
+                ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+            ) {
+                return true;
+            }
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// How long to wait before retrying a failed probe request under `policy`, with a
+/// short label of the failure; `None` when it is not retried, the wait would outlast
+/// the node's budget or `--retry-budget` is used up
+fn retry_wait(result: &ProbeResponse, policy: RetryPolicy) -> Option<(Duration, String)> {
+    let rate_limit = result
+        .as_ref()
+        .ok()
+        .and_then(|r| r.as_ref().ok())
+        .and_then(retry_after)
+        .filter(|_| policy.rate_limited);
+    let (wait, label) = match (rate_limit, result) {
+        (Some(wait), _) => (wait, "HTTP 429，按 Retry-After".to_string()),
+        _ if !policy.transient || !is_retryable(result) => return None,
+        (_, Ok(Ok(response))) => (RETRY_PAUSE, format!("HTTP {}", response.status().as_u16())),
+        (_, Ok(Err(error))) if !error.is_timeout() => (RETRY_PAUSE, "连接被重置".to_string()),
+        _ => (RETRY_PAUSE, "Timeout".to_string()),
+    };
+    let fits_budget = policy
+        .deadline
+        .is_none_or(|deadline| Instant::now() + wait < deadline);
+    (fits_budget && take_retry()).then_some((wait, label))
+}
+
+/// Latency summary, raw samples (failures as infinity), the last status timed by
+/// `--measure-error-latency`, and why the final attempt failed
+type LatencyProbe = (LatencyResult, Vec<f64>, Option<u16>, Option<FailReason>);
+
+/// Latency probes over HTTP
+async fn test_node_latency(port: u16, options: &TestOptions) -> LatencyProbe {
+    let url = &options.latency_url;
+    let test_count = options.latency_count;
+    let accept_status = &options.accept_status;
+    let proxy = match Proxy::all(proxy_url(port)) {
+        Ok(proxy) => proxy,
+        Err(e) => {
+            let error = LatencyResult::SessionError(format!("Failed to create proxy: {}", e));
+            return (error, Vec::new(), None, None);
+        }
+    };
+
+    // 每个 Client 有自己的连接池，重建 Client 就会重新握手
+    // 整体超时由每次请求外层的 timeout 控制，以便带上 --jitter 和节点预算
+    let build_client = || {
+        let mut client = test_client_builder()
+            .proxy(proxy.clone())
+            .connect_timeout(Duration::from_secs(5));
+        if accept_status.accepts_redirects() {
+            client = client.redirect(reqwest::redirect::Policy::none());
+        }
+        client.build()
+    };
+
+    let mut client = match build_client() {
+        Ok(client) => client,
+        Err(e) => {
+            let error = LatencyResult::SessionError(format!("Failed to create client: {}", e));
+            return (error, Vec::new(), None, None);
+        }
+    };
+
+    let mut latencies = Vec::new();
+    let mut http_error = None;
+    let mut failure = None;
+    // `--reuse` 时按是否为连接上的第一个请求分桶
+    let (mut fresh, mut reused) = (Vec::new(), Vec::new());
+
+    // 预热会让第一个请求变成复用连接，`--reuse` 下跳过
+    if !options.skip_latency_warmup && options.reuse.is_none() {
+        outln!("  预热连接...");
+        let warmup_timeout = options.request_timeout(Duration::from_secs(10));
+        let start = Instant::now();
+        let warmup = timeout(warmup_timeout.unwrap_or_default(), client.head(url).send()).await;
+        if options.report_warmup {
+            report_latency_warmup(warmup.is_ok_and(|r| r.is_ok()), start.elapsed());
+        }
+    }
+
+    for i in 0..test_count {
+        if i > 0 {
+            if let Some(pause) = options.attempt_pause() {
+                tokio::time::sleep(pause).await;
+            }
+        }
+        let Some(limit) = options.request_timeout(Duration::from_secs(10)) else {
+            outln!("  ↳ 节点时间预算用尽，停止剩余测试");
+            break;
+        };
+        let first_on_connection = options.reuse.is_some_and(|n| i % n == 0);
+        if first_on_connection && i > 0 {
+            if let Ok(new_client) = build_client() {
+                client = new_client;
+            }
+        }
+        let mut start = Instant::now();
+        let mut result = timeout(limit, client.head(url).send()).await;
+        if let Some((wait, label)) = retry_wait(&result, options.retry_policy()) {
+            outln!(
+                "  ↳ 第 {:2} 次: {}，等待 {:.1}s 后重试",
+                i + 1,
+                label,
+                wait.as_secs_f64()
+            );
+            tokio::time::sleep(wait).await;
+            // 等待期间预算可能已经用掉，重试的超时要重新计算
+            let Some(limit) = options.request_timeout(Duration::from_secs(10)) else {
+                outln!("  ↳ 节点时间预算用尽，停止剩余测试");
+                latencies.push(f64::INFINITY);
+                failure = Some(FailReason::Timeout);
+                break;
+            };
+            start = Instant::now();
+            result = timeout(limit, client.head(url).send()).await;
+        }
+
+        match result {
+            Ok(Ok(response)) => {
+                if accept_status.contains(response.status()) {
+                    let elapsed_ms = start.elapsed().as_micros() as f64 / 1000.0;
+                    latencies.push(elapsed_ms);
+                    if first_on_connection {
+                        fresh.push(elapsed_ms);
+                    } else {
+                        reused.push(elapsed_ms);
+                    }
+                    outln!("  ↳ 第 {:2} 次: {:>6} ms", i + 1, decimal(elapsed_ms));
+                } else if options.measure_error_latency {
+                    // 错误响应同样证明链路可用，计时但单独标记状态
+                    let elapsed_ms = start.elapsed().as_micros() as f64 / 1000.0;
+                    latencies.push(elapsed_ms);
+                    http_error = Some(response.status().as_u16());
+                    outln!(
+                        "  ↳ 第 {:2} 次: {:>6} ms (HTTP Error {})",
+                        i + 1,
+                        decimal(elapsed_ms),
+                        response.status()
+                    );
+                } else {
+                    latencies.push(f64::INFINITY);
+                    failure = Some(FailReason::Http);
+                    outln!("  ↳ 第 {:2} 次: HTTP Error {}", i + 1, response.status());
+                    break;
+                }
+            }
+            // 证书问题每次都会复现，不必再试
+            Ok(Err(e)) if is_tls_error(&e) => {
+                outln!("  ↳ 第 {:2} 次: TLS Error ({})", i + 1, e);
+                return (
+                    LatencyResult::TlsError(e.to_string()),
+                    latencies,
+                    http_error,
+                    Some(FailReason::Tls),
+                );
+            }
+            Ok(Err(e)) if e.is_timeout() => {
+                if record_timeout(i + 1, start, limit, options, &mut latencies, &mut failure) {
+                    break;
+                }
+            }
+            Ok(Err(e)) => {
+                latencies.push(f64::INFINITY);
+                failure = Some(FailReason::Connect);
+                outln!("  ↳ 第 {:2} 次: Error ({})", i + 1, e);
+                break;
+            }
+            Err(_) => {
+                if record_timeout(i + 1, start, limit, options, &mut latencies, &mut failure) {
+                    break;
+                }
+            }
+        }
+    }
+
+    if options.reuse.is_some() {
+        let describe = |samples: &mut Vec<f64>| match samples.len() {
+            0 => "-".to_string(),
+            n => {
+                samples.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+                format!("med {} ms ({n} 次)", decimal(median(samples)))
+            }
+        };
+        outln!(
+            "  ↳ 新连接: {} | 复用连接: {}",
+            describe(&mut fresh),
+            describe(&mut reused)
+        );
+    }
+
+    (
+        summarize_latencies(&latencies, test_count, options),
+        latencies,
+        http_error,
+        failure,
+    )
+}
+
+/// Record a timed-out latency attempt, shared by the HTTP and tcp-ping probes;
+/// returns whether the remaining attempts should be skipped
+fn record_timeout(
+    attempt: usize,
+    start: Instant,
+    limit: Duration,
+    options: &TestOptions,
+    latencies: &mut Vec<f64>,
+    failure: &mut Option<FailReason>,
+) -> bool {
+    // 预算耗尽导致的超时不算作节点失败，按已有样本归类
+    if options.budget_exhausted() {
+        outln!("  ↳ 第 {:2} 次: 节点时间预算用尽，停止剩余测试", attempt);
+        return true;
+    }
+    // --timeout-as-slow 时超时记为超时时长本身，节点算作很慢而不是失败
+    if options.timeout_as_slow {
+        let elapsed_ms = start.elapsed().min(limit).as_micros() as f64 / 1000.0;
+        latencies.push(elapsed_ms);
+        outln!(
+            "  ↳ 第 {:2} 次: {:>6} ms (Timeout)",
+            attempt,
+            decimal(elapsed_ms)
+        );
+        return false;
+    }
+    latencies.push(f64::INFINITY);
+    *failure = Some(FailReason::Timeout);
+    outln!("  ↳ 第 {:2} 次: Timeout", attempt);
+    true
+}
+
+/// Median of non-empty, ascending values; an even count averages the two middle ones
+fn median(sorted: &[f64]) -> f64 {
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    }
+}
+
+/// Classify raw samples (failed attempts as infinity) into a `LatencyResult`
+fn summarize_latencies(
+    latencies: &[f64],
+    test_count: usize,
+    options: &TestOptions,
+) -> LatencyResult {
+    if latencies.is_empty() || latencies.iter().all(|&l| l.is_infinite()) {
+        return LatencyResult::AllFailed;
+    }
+
+    let valid_latencies: Vec<f64> = latencies
+        .iter()
+        .copied()
+        .filter(|&l| !l.is_infinite())
+        .collect();
+
+    if valid_latencies.len() < options.min_valid {
+        return LatencyResult::Unstable(valid_latencies.len(), test_count);
+    }
+
+    let mut sorted = valid_latencies;
+    sorted.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+    let median = median(&sorted);
+    let average = sorted.iter().sum::<f64>() / sorted.len() as f64;
+    // 两端各去掉 trim_fraction 比例的样本后再取平均
+    let trimmed_mean = options.trim_fraction.map(|fraction| {
+        let cut = (sorted.len() as f64 * fraction).floor() as usize;
+        let kept = &sorted[cut..sorted.len() - cut];
+        kept.iter().sum::<f64>() / kept.len() as f64
+    });
+    let ci = (options.confidence_interval && sorted.len() > 1).then(|| {
+        let n = sorted.len() as f64;
+        let variance = sorted.iter().map(|l| (l - average).powi(2)).sum::<f64>() / (n - 1.0);
+        t_critical_95(sorted.len() - 1) * (variance / n).sqrt()
+    });
+
+    LatencyResult::Success {
+        median,
+        average,
+        minimum: *sorted.first().unwrap(),
+        maximum: *sorted.last().unwrap(),
+        trimmed_mean,
+        ci,
+    }
+}
+
+/// Two-sided 95% critical value of Student's t with `df` degrees of freedom; beyond
+/// the table the normal approximation is close enough
+fn t_critical_95(df: usize) -> f64 {
+    const TABLE: [f64; 30] = [
+        12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+        2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+        2.052, 2.048, 2.045, 2.042,
+    ];
+    TABLE.get(df.wrapping_sub(1)).copied().unwrap_or(1.96)
+}
+
+/// Print the cold-connection warmup time for `--report-warmup`
+fn report_latency_warmup(ok: bool, elapsed: Duration) {
+    if ok {
+        let ms = elapsed.as_micros() as f64 / 1000.0;
+        outln!("  ↳ 预热 (冷连接，含握手): {:>6} ms", decimal(ms));
+    } else {
+        outln!("  ↳ 预热失败");
+    }
+}
+
+/// Time proxy CONNECT handshakes to `target` instead of HTTP requests
+///
+/// This measures the proxy's path to the target without TLS or HTTP on top
+async fn test_node_tcp_ping(
+    port: u16,
+    target: &ProbeHost,
+    options: &TestOptions,
+) -> (LatencyResult, Vec<f64>, Option<FailReason>) {
+    let test_count = options.latency_count;
+    let mut latencies = Vec::new();
+    let mut failure = None;
+
+    if !options.skip_latency_warmup {
+        outln!("  预热连接...");
+        let warmup_timeout = options.request_timeout(Duration::from_secs(10));
+        let start = Instant::now();
+        let warmup = timeout(
+            warmup_timeout.unwrap_or_default(),
+            proxy_connect(port, target),
+        )
+        .await;
+        if options.report_warmup {
+            report_latency_warmup(warmup.is_ok_and(|r| r.is_ok()), start.elapsed());
+        }
+    }
+
+    for i in 0..test_count {
+        if i > 0 {
+            if let Some(pause) = options.attempt_pause() {
+                tokio::time::sleep(pause).await;
+            }
+        }
+        let Some(limit) = options.request_timeout(Duration::from_secs(10)) else {
+            outln!("  ↳ 节点时间预算用尽，停止剩余测试");
+            break;
+        };
+        let start = Instant::now();
+        match timeout(limit, proxy_connect(port, target)).await {
+            Ok(Ok(_)) => {
+                let elapsed_ms = start.elapsed().as_micros() as f64 / 1000.0;
+                latencies.push(elapsed_ms);
+                outln!("  ↳ 第 {:2} 次: {:>6} ms", i + 1, decimal(elapsed_ms));
+            }
+            Ok(Err(e)) => {
+                latencies.push(f64::INFINITY);
+                failure = Some(FailReason::Connect);
+                outln!("  ↳ 第 {:2} 次: Error ({})", i + 1, e);
+                break;
+            }
+            Err(_) => {
+                if record_timeout(i + 1, start, limit, options, &mut latencies, &mut failure) {
+                    break;
+                }
+            }
+        }
+    }
+
+    (
+        summarize_latencies(&latencies, test_count, options),
+        latencies,
+        failure,
+    )
+}
+
+/// Set by `--user-agent`; otherwise reqwest's default is sent
+static USER_AGENT: OnceLock<String> = OnceLock::new();
+
+/// Set by `--danger-accept-invalid-certs`
+static ACCEPT_INVALID_CERTS: AtomicBool = AtomicBool::new(false);
+
+/// Whether `--dns-cache` keeps local name lookups for the whole run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum DnsCache {
+    On,
+    Off,
+}
+
+/// Set by `--dns-cache on`; shared by every test client, though only direct requests
+/// resolve through it
+static DNS_CACHE: OnceLock<std::sync::Arc<CachingResolver>> = OnceLock::new();
+
+/// How long a cached lookup is reused; the system resolver does not report record TTLs
+const DNS_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Resolves through the system resolver, remembering each answer for `DNS_CACHE_TTL`
+#[derive(Default)]
+struct CachingResolver {
+    entries: std::sync::Mutex<HashMap<String, (Instant, Vec<std::net::SocketAddr>)>>,
+}
+
+impl reqwest::dns::Resolve for CachingResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let host = name.as_str().to_string();
+        let cached = self
+            .entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(&host)
+            .filter(|(at, _)| at.elapsed() < DNS_CACHE_TTL)
+            .map(|(_, addrs)| addrs.clone());
+        // 'static 的 future 里拿不到 &self，只能取全局那份
+        let cache = DNS_CACHE.get().cloned();
+        Box::pin(async move {
+            let addrs = match cached {
+                Some(addrs) => addrs,
+                None => {
+                    let addrs: Vec<_> =
+                        tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+                    if let Some(cache) = cache {
+                        cache
+                            .entries
+                            .lock()
+                            .unwrap_or_else(|poisoned| poisoned.into_inner())
+                            .insert(host, (Instant::now(), addrs.clone()));
+                    }
+                    addrs
+                }
+            };
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// `Client::builder()` for requests sent through a node, with the chosen user agent
+fn test_client_builder() -> reqwest::ClientBuilder {
+    let mut builder = Client::builder()
+        .tls_danger_accept_invalid_certs(ACCEPT_INVALID_CERTS.load(Ordering::Relaxed));
+    if let Some(resolver) = DNS_CACHE.get() {
+        builder = builder.dns_resolver(resolver.clone());
+    }
+    match USER_AGENT.get() {
+        Some(user_agent) => builder.user_agent(user_agent),
+        None => builder,
+    }
+}
+
+/// Whether a request failed in the TLS layer (bad certificate, handshake alert)
+/// rather than at the proxy or on the network
+fn is_tls_error(error: &reqwest::Error) -> bool {
+    use tokio_rustls::rustls;
+
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(err) = source {
+        if err.is::<rustls::Error>() {
+            return true;
+        }
+        // rustls 的错误被层层包在 io::Error 里，而 io::Error::source 会跳过被包的错误本身
+        source = match err.downcast_ref::<std::io::Error>() {
+            Some(io) => io
+                .get_ref()
+                .map(|inner| inner as &(dyn std::error::Error + 'static)),
+            None => err.source(),
+        };
+    }
+    false
+}
+
+/// Proxy scheme per port found by `--autodetect-protocol`; ports not listed are socks
+static DETECTED_SCHEMES: OnceLock<HashMap<u16, &'static str>> = OnceLock::new();
+
+/// Credentials per port from the inbounds' `users`, picked by `--user-index`
+static PROXY_AUTH: OnceLock<HashMap<u16, InboundUser>> = OnceLock::new();
+
+fn proxy_auth(port: u16) -> Option<&'static InboundUser> {
+    PROXY_AUTH.get().and_then(|auth| auth.get(&port))
+}
+
+/// Proxies given with `--socks`, keyed by their position in the list, which stands in
+/// for the port; without `--socks` every port is a local inbound
+static AD_HOC_PROXIES: OnceLock<HashMap<u16, ProbeHost>> = OnceLock::new();
+
+/// Address of the proxy tested as `port`
+fn proxy_address(port: u16) -> ProbeHost {
+    AD_HOC_PROXIES
+        .get()
+        .and_then(|proxies| proxies.get(&port).cloned())
+        .unwrap_or_else(|| ProbeHost {
+            host: "127.0.0.1".to_string(),
+            port,
+        })
+}
+
+/// Proxy URL of the inbound on `port`, with its credentials if it has any
+fn proxy_url(port: u16) -> String {
+    let scheme = DETECTED_SCHEMES
+        .get()
+        .and_then(|schemes| schemes.get(&port).copied())
+        .unwrap_or("socks5h");
+    let url = format!("{scheme}://{}", proxy_address(port));
+    let Some(user) = proxy_auth(port) else {
+        return url;
+    };
+    // Url 负责对用户名和密码中的特殊字符做百分号编码
+    let mut url = reqwest::Url::parse(&url).expect("scheme and address are always valid");
+    let _ = url.set_username(&user.username);
+    let _ = url.set_password(Some(&user.password));
+    url.to_string()
+}
+
+/// Find out whether a local port speaks socks5 or HTTP proxy, trying socks5 first
+async fn detect_protocol(port: u16, target: &ProbeHost) -> Option<&'static str> {
+    if let Ok(Ok(_)) = timeout(Duration::from_secs(3), socks_connect(port, target)).await {
+        return Some("socks5h");
+    }
+    match timeout(Duration::from_secs(3), http_connect(port, target)).await {
+        Ok(Ok(_)) => Some("http"),
+        // 凭据被拒绝同样说明对端是 HTTP 代理，测试时会报告认证失败
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::PermissionDenied => Some("http"),
+        _ => None,
+    }
+}
+
+/// Open a tunnel to `target` through the node in whichever protocol its port speaks
+async fn proxy_connect(port: u16, target: &ProbeHost) -> std::io::Result<tokio::net::TcpStream> {
+    if proxy_url(port).starts_with("http:") {
+        http_connect(port, target).await
+    } else {
+        socks_connect(port, target).await
+    }
+}
+
+/// HTTP CONNECT request for `target`, with Basic credentials if the inbound has a user
+fn connect_request(target: &ProbeHost, auth: Option<&InboundUser>) -> String {
+    use base64::Engine;
+
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if let Some(user) = auth {
+        let credentials = format!("{}:{}", user.username, user.password);
+        let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+        request.push_str(&format!("Proxy-Authorization: Basic {encoded}\r\n"));
+    }
+    request.push_str("\r\n");
+    request
+}
+
+/// Open a fresh connection to the local HTTP proxy and CONNECT to `target`
+async fn http_connect(port: u16, target: &ProbeHost) -> std::io::Result<tokio::net::TcpStream> {
+    use std::io::{Error, ErrorKind};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let address = proxy_address(port);
+    let mut stream = tokio::net::TcpStream::connect((address.host.as_str(), address.port)).await?;
+    stream
+        .write_all(connect_request(target, proxy_auth(port)).as_bytes())
+        .await?;
+
+    // 逐字节读到空行为止，之后的字节才属于隧道
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > 8192 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "HTTP proxy reply too long",
+            ));
+        }
+        head.push(stream.read_u8().await?);
+    }
+    let status = head
+        .strip_prefix(b"HTTP/1.")
+        .and_then(|rest| rest.get(2..5))
+        .and_then(|code| std::str::from_utf8(code).ok()?.parse::<u16>().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "not an HTTP proxy"))?;
+    match status {
+        200..=299 => Ok(stream),
+        407 => Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "HTTP proxy rejected the credentials",
+        )),
+        _ => Err(Error::other(format!(
+            "HTTP proxy CONNECT failed (status {status})"
+        ))),
+    }
+}
+
+/// Open a fresh connection to the local socks inbound and CONNECT to `target`
+async fn socks_connect(port: u16, target: &ProbeHost) -> std::io::Result<tokio::net::TcpStream> {
+    use std::io::{Error, ErrorKind};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let address = proxy_address(port);
+    let mut stream = tokio::net::TcpStream::connect((address.host.as_str(), address.port)).await?;
+
+    // inbound 声明了用户时用用户名/密码认证 (RFC 1929)，否则无认证
+    let auth = proxy_auth(port);
+    let method = if auth.is_some() { 0x02 } else { 0x00 };
+    stream.write_all(&[0x05, 0x01, method]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [0x05, method] {
+        return Err(Error::new(ErrorKind::InvalidData, "socks5 auth rejected"));
+    }
+    if let Some(user) = auth {
+        // RFC 1929 用一个字节表示长度
+        let (Ok(username_len), Ok(password_len)) = (
+            u8::try_from(user.username.len()),
+            u8::try_from(user.password.len()),
+        ) else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "socks5 username or password longer than 255 bytes",
+            ));
+        };
+        let mut request = vec![0x01, username_len];
+        request.extend_from_slice(user.username.as_bytes());
+        request.push(password_len);
+        request.extend_from_slice(user.password.as_bytes());
+        stream.write_all(&request).await?;
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "socks5 username/password rejected",
+            ));
+        }
+    }
+
+    let mut request = vec![0x05, 0x01, 0x00];
+    match target.host.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V4(ip)) => {
+            request.push(0x01);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(std::net::IpAddr::V6(ip)) => {
+            request.push(0x04);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            request.push(0x03);
+            request.push(target.host.len() as u8);
+            request.extend_from_slice(target.host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&target.port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0x00 {
+        return Err(Error::other(format!(
+            "socks5 connect failed (reply {})",
+            reply[1]
+        )));
+    }
+    // 跳过 BND.ADDR 和 BND.PORT，之后的字节才属于隧道
+    let address_len = match reply[3] {
+        0x01 => 4,
+        0x04 => 16,
+        _ => stream.read_u8().await? as usize,
+    };
+    let mut bound = vec![0u8; address_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(stream)
+}
+
+/// Complete a TLS handshake with the latency URL's host through the node and report
+/// what was negotiated; the certificate is checked against the system's roots
+async fn test_node_tls(port: u16, url: &str) -> TlsCheck {
+    use rustls_platform_verifier::ConfigVerifierExt;
+    use tokio_rustls::rustls::{pki_types::ServerName, ClientConfig};
+
+    let url = match reqwest::Url::parse(url) {
+        Ok(url) if url.scheme() == "https" => url,
+        _ => return TlsCheck::Failed("测试地址不是 https".to_string()),
+    };
+    let Some(host) = url.host_str() else {
+        return TlsCheck::Failed("测试地址没有主机名".to_string());
+    };
+    let target = ProbeHost {
+        host: host.to_string(),
+        port: url.port_or_known_default().unwrap_or(443),
+    };
+    let (config, server_name) = match (
+        ClientConfig::with_platform_verifier(),
+        ServerName::try_from(host.to_string()),
+    ) {
+        (Ok(config), Ok(server_name)) => (config, server_name),
+        (Err(e), _) => return TlsCheck::Failed(format!("Failed to create TLS config: {}", e)),
+        (_, Err(e)) => return TlsCheck::Failed(e.to_string()),
+    };
+
+    let handshake = async {
+        let stream = proxy_connect(port, &target).await?;
+        tokio_rustls::TlsConnector::from(std::sync::Arc::new(config))
+            .connect(server_name, stream)
+            .await
+    };
+    match timeout(Duration::from_secs(10), handshake).await {
+        Ok(Ok(stream)) => {
+            let (_, connection) = stream.get_ref();
+            TlsCheck::Negotiated {
+                version: connection
+                    .protocol_version()
+                    .map_or_else(|| "-".to_string(), |v| format!("{v:?}")),
+                cipher: connection
+                    .negotiated_cipher_suite()
+                    .map_or_else(|| "-".to_string(), |c| format!("{:?}", c.suite())),
+            }
+        }
+        // 证书校验失败也会在这里出现，可能是节点在做中间人
+        Ok(Err(e)) => TlsCheck::Failed(e.to_string()),
+        Err(_) => TlsCheck::Failed("Timeout".to_string()),
+    }
+}
+
+/// Download `size` bytes through the node; the second value is the peak sustained
+/// speed in Mbps over a `PEAK_WINDOW`, if the download lasted that long
+async fn test_node_speed(
+    port: u16,
+    size: u64,
+    warmup: bool,
+    options: &TestOptions,
+) -> (SpeedResult, Option<f64>) {
+    let proxy = match Proxy::all(proxy_url(port)) {
+        Ok(proxy) => proxy,
+        Err(e) => {
+            let error = SpeedResult::failed(
+                FailReason::Session,
+                format!("Failed to create proxy: {}", e),
+            );
+            return (error, None);
+        }
+    };
+
+    // 下载大小不设上限，因此不限制总时长，只限制单次读取的间隔
+    let client = test_client_builder()
+        .proxy(proxy)
+        .read_timeout(Duration::from_secs(30))
+        .connect_timeout(Duration::from_secs(10))
+        .build();
+
+    let client = match client {
+        Ok(client) => client,
+        Err(e) => {
+            let error = SpeedResult::failed(
+                FailReason::Session,
+                format!("Failed to create client: {}", e),
+            );
+            return (error, None);
+        }
+    };
+
+    if warmup {
+        outln!("  预热下载...");
+        let warmup_url = download_url(WARMUP_DOWNLOAD_BYTES);
+        let start = Instant::now();
+        let downloaded = match timeout(Duration::from_secs(30), client.get(warmup_url).send()).await
+        {
+            Ok(Ok(response)) => drain_body(response).await.ok(),
+            _ => None,
+        };
+        if options.report_warmup {
+            match downloaded {
+                Some(bytes) => outln!(
+                    "  ↳ 预热下载 (冷连接): {} in {:.2}s → {}",
+                    format_bytes(bytes),
+                    start.elapsed().as_secs_f64(),
+                    SpeedResult::Success(throughput_mbps(bytes, start.elapsed()))
+                ),
+                None => outln!("  ↳ 预热下载失败"),
+            }
+        }
+    }
+
+    outln!("  开始下载测试 ({})...", format_bytes(size));
+    if let Some(segments) = options.segments.filter(|&segments| segments > 1) {
+        let url = download_url(size);
+        let retry = options.retry_policy();
+        match measure_segmented(&client, &url, size, segments, retry, options.abort_floor()).await {
+            Some(result) => return result,
+            None => outln!("  ↳ 下载地址不支持 Range 请求，改为单连接下载"),
+        }
+    }
+    measure_download(
+        &client,
+        &download_url(size),
+        options.retry_policy(),
+        options.abort_floor(),
+    )
+    .await
+}
+
+/// Send `request` under `limit`, retrying it once as `retry` allows; also returns
+/// when the attempt that counts was started
+async fn send_with_retry(
+    request: reqwest::RequestBuilder,
+    limit: Duration,
+    retry: RetryPolicy,
+) -> (ProbeResponse, Instant) {
+    let send = || {
+        request
+            .try_clone()
+            .expect("probe requests have no streaming body")
+            .send()
+    };
+    let mut start = Instant::now();
+    let mut result = timeout(limit, send()).await;
+    if let Some((wait, label)) = retry_wait(&result, retry) {
+        outln!("  ↳ {}，等待 {:.1}s 后重试", label, wait.as_secs_f64());
+        tokio::time::sleep(wait).await;
+        // 等待期间用掉的预算不能再用于重试
+        let limit = retry.deadline.map_or(limit, |deadline| {
+            limit.min(deadline.saturating_duration_since(Instant::now()))
+        });
+        start = Instant::now();
+        result = timeout(limit, send()).await;
+    }
+    (result, start)
+}
+
+/// How long a download waits for the response headers; the body is only bounded by
+/// the client's read timeout, since download sizes have no upper limit
+const DOWNLOAD_RESPONSE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Failure of a download request that got no response
+fn request_failure(result: &ProbeResponse, limit: Duration) -> SpeedResult {
+    match result {
+        Ok(Err(e)) if is_tls_error(e) => SpeedResult::failed(FailReason::Tls, e.to_string()),
+        Ok(Err(e)) => SpeedResult::failed(FailReason::Connect, e.to_string()),
+        _ => SpeedResult::failed(
+            FailReason::Timeout,
+            format!("no response within {}s", limit.as_secs()),
+        ),
+    }
+}
+
+/// Speed and peak speed of a finished (or, when `aborted`, abandoned) download
+fn finish_download(
+    bytes: u64,
+    progress: &[(f64, u64)],
+    aborted: bool,
+    elapsed: Duration,
+) -> (SpeedResult, Option<f64>) {
+    let speed_mbps = throughput_mbps(bytes, elapsed);
+    if aborted {
+        outln!(
+            "  ↳ {:.2}s 内只有 {}，远低于 --speed-floor，提前停止下载",
+            elapsed.as_secs_f64(),
+            SpeedResult::Success(speed_mbps)
+        );
+        return (SpeedResult::TooSlow(speed_mbps), None);
+    }
+    let peak_mbps =
+        peak_window_rate(progress, PEAK_WINDOW.as_secs_f64()).map(|rate| rate * 8.0 / 1_000_000.0);
+    outln!(
+        "  ↳ 下载完成: {:.2} MiB in {:.2}s → {}",
+        bytes as f64 / 1024.0 / 1024.0,
+        elapsed.as_secs_f64(),
+        SpeedResult::Success(speed_mbps)
+    );
+    if let Some(peak) = peak_mbps {
+        outln!(
+            "  ↳ 峰值持续速度 ({}s 窗口): {}",
+            PEAK_WINDOW.as_secs(),
+            SpeedResult::Success(peak)
+        );
+    }
+    (SpeedResult::Success(speed_mbps), peak_mbps)
+}
+
+/// Download `size` bytes of `url` as `segments` concurrent ranged requests and time
+/// them together; `None` when the server doesn't answer with `Accept-Ranges: bytes`
+///
+/// Each segment is held to its share of `abort_below`
+async fn measure_segmented(
+    client: &Client,
+    url: &str,
+    size: u64,
+    segments: usize,
+    retry: RetryPolicy,
+    abort_below: Option<f64>,
+) -> Option<(SpeedResult, Option<f64>)> {
+    use reqwest::header::{ACCEPT_RANGES, RANGE};
+
+    const HEAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+    // HEAD 本身失败说明节点有问题，照常记为失败，而不是当作不支持 Range
+    let (probe, _) = send_with_retry(client.head(url), HEAD_TIMEOUT, retry).await;
+    let probe = match probe {
+        Ok(Ok(response)) => response,
+        failed => return Some((request_failure(&failed, HEAD_TIMEOUT), None)),
+    };
+    let ranged = probe
+        .headers()
+        .get(ACCEPT_RANGES)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("bytes"));
+    if !probe.status().is_success() || !ranged {
+        return None;
+    }
+    // 服务器给出的文件比请求的小时，只按实际大小分段
+    let size = probe
+        .content_length()
+        .map_or(size, |length| length.min(size));
+    let segment = size.div_ceil(segments as u64).max(1);
+    outln!(
+        "  ↳ 分 {} 段并发下载，每段 {}",
+        segments,
+        format_bytes(segment)
+    );
+
+    let start = Instant::now();
+    let abort_below = abort_below.map(|floor| floor / segments as f64);
+    let mut downloads = tokio::task::JoinSet::new();
+    for from in (0..size).step_by(segment as usize) {
+        let to = (from + segment).min(size) - 1;
+        let request = client.get(url).header(RANGE, format!("bytes={from}-{to}"));
+        downloads.spawn(async move {
+            let (result, _) = send_with_retry(request, DOWNLOAD_RESPONSE_TIMEOUT, retry).await;
+            let response = match result {
+                Ok(Ok(response)) => response,
+                failed => return Err(request_failure(&failed, DOWNLOAD_RESPONSE_TIMEOUT)),
+            };
+            // 不是 206 说明服务器忽略了 Range，每段都会下载整个文件
+            if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+                let error = format!("ranged request answered with {}", response.status());
+                return Err(SpeedResult::failed(FailReason::Http, error));
+            }
+            drain_body_sampled(response, start, abort_below)
+                .await
+                .map_err(|e| {
+                    SpeedResult::failed(
+                        FailReason::Stream,
+                        format!("Failed to read response: {}", e),
+                    )
+                })
+        });
+    }
+    // 与单连接下载一样不限制总时长，卡住的分段由客户端的读取超时结束
+    let mut finished = Vec::new();
+    while let Some(joined) = downloads.join_next().await {
+        match joined.expect("download tasks don't panic") {
+            Ok(segment) => finished.push(segment),
+            Err(failed) => return Some((failed, None)),
+        }
+    }
+    // 把各段的进度合并成整体进度，峰值速度按合计吞吐量计算
+    let mut steps: Vec<(f64, u64)> = finished
+        .iter()
+        .flat_map(|(_, progress, _)| {
+            progress
+                .windows(2)
+                .map(|pair| (pair[1].0, pair[1].1 - pair[0].1))
+        })
+        .collect();
+    steps.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut progress = vec![(0.0, 0)];
+    for (at, bytes) in steps {
+        let total = progress.last().map_or(0, |&(_, total)| total);
+        progress.push((at, total + bytes));
+    }
+    let bytes = finished.iter().map(|(bytes, _, _)| bytes).sum();
+    let aborted = finished.iter().any(|(_, _, aborted)| *aborted);
+    Some(finish_download(bytes, &progress, aborted, start.elapsed()))
+}
+
+/// Time one download of `url`, split out of `test_node_speed` so that tests can
+/// point it at a local server
+async fn measure_download(
+    client: &Client,
+    url: &str,
+    retry: RetryPolicy,
+    abort_below: Option<f64>,
+) -> (SpeedResult, Option<f64>) {
+    match send_with_retry(client.get(url), DOWNLOAD_RESPONSE_TIMEOUT, retry).await {
+        (Ok(Ok(response)), start) if response.status().is_success() => {
+            match drain_body_sampled(response, start, abort_below).await {
+                Ok((bytes, progress, aborted)) => {
+                    finish_download(bytes, &progress, aborted, start.elapsed())
+                }
+                Err(e) => (
+                    SpeedResult::failed(
+                        FailReason::Stream,
+                        format!("Failed to read response: {}", e),
+                    ),
+                    None,
+                ),
+            }
+        }
+        (Ok(Ok(response)), _) => {
+            let error = SpeedResult::failed(FailReason::Http, response.status().to_string());
+            (error, None)
+        }
+        (failed, _) => (request_failure(&failed, DOWNLOAD_RESPONSE_TIMEOUT), None),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Grade {
+    A,
+    B,
+    C,
+    D,
+    F,
+}
+
+impl Grade {
+    const PASSING: [Grade; 4] = [Grade::A, Grade::B, Grade::C, Grade::D];
+
+    fn letter(self) -> char {
+        match self {
+            Grade::A => 'A',
+            Grade::B => 'B',
+            Grade::C => 'C',
+            Grade::D => 'D',
+            Grade::F => 'F',
+        }
+    }
+
+    /// ANSI color code for terminal output
+    fn color(self) -> &'static str {
+        match self {
+            Grade::A => "32",
+            Grade::B => "36",
+            Grade::C => "33",
+            Grade::D => "35",
+            Grade::F => "31",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct GradeThresholds {
+    latency: [f64; 4], // upper bounds in ms, ascending
+    speed: [f64; 4],   // lower bounds in Mbps, descending
+}
+
+impl GradeThresholds {
+    const DEFAULT_LATENCY: [f64; 4] = [100.0, 200.0, 400.0, 800.0];
+    const DEFAULT_SPEED: [f64; 4] = [50.0, 20.0, 10.0, 5.0];
+
+    fn new(latency: Vec<f64>, speed: Vec<f64>) -> Result<Self, String> {
+        let latency = match latency.len() {
+            0 => Self::DEFAULT_LATENCY,
+            _ => <[f64; 4]>::try_from(latency)
+                .map_err(|_| "--grade-latency 需要恰好 4 个阈值".to_string())?,
+        };
+        let speed = match speed.len() {
+            0 => Self::DEFAULT_SPEED,
+            _ => <[f64; 4]>::try_from(speed)
+                .map_err(|_| "--grade-speed 需要恰好 4 个阈值".to_string())?,
+        };
+        if !latency.is_sorted() {
+            return Err("--grade-latency 阈值必须从小到大排列".to_string());
+        }
+        if !speed.is_sorted_by(|a, b| a >= b) {
+            return Err("--grade-speed 阈值必须从大到小排列".to_string());
+        }
+        Ok(GradeThresholds { latency, speed })
+    }
+
+    /// The worse of the latency grade and (if tested) the speed grade
+    fn grade(&self, result: &NodeResult) -> Grade {
+        let latency_grade = match result.latency {
+            LatencyResult::Success { median, .. } => Self::pick(|i| median <= self.latency[i]),
+            _ => Grade::F,
+        };
+        let speed_grade = match result.speed {
+            Some(SpeedResult::Success(speed)) => Self::pick(|i| speed >= self.speed[i]),
+            Some(_) => Grade::F,
+            None => Grade::A,
+        };
+        latency_grade.max(speed_grade)
+    }
+
+    fn pick(within: impl Fn(usize) -> bool) -> Grade {
+        (0..Grade::PASSING.len())
+            .find(|&i| within(i))
+            .map_or(Grade::F, |i| Grade::PASSING[i])
+    }
+}
+
+/// Probe settings shared by every node in a run (`deadline` is filled in per node)
+#[derive(Clone, Default, Serialize)]
+struct TestOptions {
+    latency_count: usize,
+    min_valid: usize, // successful samples required for `LatencyResult::Success`
+    download_size: Option<u64>,
+    sweep_sizes: Vec<u64>, // smaller `-d` sizes downloaded before `download_size`
+    segments: Option<usize>, // concurrent ranged requests per download, `--segments`
+    accept_status: StatusRanges,
+    measure_error_latency: bool,
+    timeout_as_slow: bool,
+    speed_warmup: bool,
+    skip_latency_warmup: bool,
+    report_warmup: bool,
+    latency_under_load: bool,
+    load_levels: Vec<usize>,
+    tcp_ping: Option<ProbeHost>,
+    direct_ip: Option<String>,  // set by `--leak-check`
+    trim_fraction: Option<f64>, // set by `--robust`
+    confidence_interval: bool,
+    #[serde(serialize_with = "serialize_secs")]
+    node_budget: Option<Duration>,
+    #[serde(skip)]
+    deadline: Option<Instant>, // start of the node + `node_budget`
+    #[serde(serialize_with = "serialize_secs")]
+    min_download_time: Option<Duration>,
+    speed_samples: usize,     // downloads per node, the median is reported
+    speed_floor: Option<f64>, // Mbps, slower downloads become `SpeedResult::TooSlow`
+    abort_below_floor: bool,
+    overlap_probes: bool, // run the latency and speed tests concurrently
+    show_tls: bool,
+    reuse: Option<usize>, // requests per connection before the client is rebuilt
+    detect_country: bool,
+    histogram_bucket: Option<f64>, // bucket width in ms, set by `--histogram`
+    latency_url: String,
+    node_targets: HashMap<String, String>, // tag -> own latency URL, `--respect-node-target`
+    honor_retry_after: bool,
+    retry_transient: bool,
+    #[serde(serialize_with = "serialize_secs")]
+    think_time: Option<Duration>, // pause between latency attempts
+    jitter: bool,
+    dns_probe: bool,
+    check_ipv6: bool,
+    #[serde(skip)]
+    defer_speed: bool, // the speed test runs later, in its own phase
+}
+
+// `--print-config` 中按命令行参数的写法输出
+impl Serialize for ProbeHost {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Serialize for StatusRanges {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Durations in `--print-config` output as seconds, like the flags that set them
+fn serialize_secs<S: serde::Serializer>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_some(&duration.map(|d| d.as_secs_f64()))
+}
+
+/// Largest share of a timeout or think time that `--jitter` adds
+const JITTER_FRACTION: f64 = 0.1;
+const JITTER_MAX_PAUSE: Duration = Duration::from_millis(50);
+
+/// Uniformly random duration in `[0, max)`
+///
+/// Each `RandomState` gets fresh random keys, which is plenty for spreading out
+/// timings without pulling in a random number crate
+fn random_up_to(max: Duration) -> Duration {
+    use std::hash::{BuildHasher, Hasher};
+
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    max.mul_f64(random as f64 / (u64::MAX as f64 + 1.0))
+}
+
+impl TestOptions {
+    /// Copy with the `--node-budget` deadline starting now; `None` without a budget
+    fn budgeted(&self) -> Option<TestOptions> {
+        self.node_budget.map(|budget| TestOptions {
+            deadline: Some(Instant::now() + budget),
+            ..self.clone()
+        })
+    }
+
+    /// Copy probing the node's own `speedtest_url`; `None` if it has none
+    fn for_node(&self, tag: &str) -> Option<TestOptions> {
+        self.node_targets.get(tag).map(|url| TestOptions {
+            latency_url: url.clone(),
+            ..self.clone()
+        })
+    }
+
+    /// Pause before the next latency attempt: `--think-time`, plus `--jitter`
+    fn attempt_pause(&self) -> Option<Duration> {
+        if !self.jitter {
+            return self.think_time;
+        }
+        let spread = self
+            .think_time
+            .map_or(JITTER_MAX_PAUSE, |think| think.mul_f64(JITTER_FRACTION));
+        Some(self.think_time.unwrap_or_default() + random_up_to(spread))
+    }
+
+    /// Time left of the current node's `--node-budget`, if there is one
+    fn remaining_budget(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            rate_limited: self.honor_retry_after,
+            transient: self.retry_transient,
+            deadline: self.deadline,
+        }
+    }
+
+    /// Throughput in Mbps below which a running download is abandoned
+    fn abort_floor(&self) -> Option<f64> {
+        self.speed_floor.filter(|_| self.abort_below_floor)
+    }
+
+    fn budget_exhausted(&self) -> bool {
+        self.remaining_budget().is_some_and(|left| left.is_zero())
+    }
+
+    /// `default`, shortened to the remaining budget; `None` once it is used up
+    fn request_timeout(&self, default: Duration) -> Option<Duration> {
+        let default = if self.jitter {
+            default + random_up_to(default.mul_f64(JITTER_FRACTION))
+        } else {
+            default
+        };
+        match self.remaining_budget() {
+            None => Some(default),
+            Some(left) => (!left.is_zero()).then(|| left.min(default)),
+        }
+    }
+}
+
+/// Run `concurrency` latency probes (and downloads) in parallel through one node
+async fn test_node_load(port: u16, concurrency: usize, options: &TestOptions) -> LoadLevelResult {
+    const PROBES_PER_TASK: usize = 3;
+
+    let mut result = LoadLevelResult {
+        concurrency,
+        probes_ok: 0,
+        probes_total: concurrency * PROBES_PER_TASK,
+        median_latency: None,
+        aggregate_speed: None,
+    };
+
+    // 与速度测试一样不限制下载总时长，只限制单次读取的间隔
+    let client = match Proxy::all(proxy_url(port)).and_then(|proxy| {
+        test_client_builder()
+            .proxy(proxy)
+            .read_timeout(Duration::from_secs(30))
+            .connect_timeout(Duration::from_secs(10))
+            .build()
+    }) {
+        Ok(client) => client,
+        Err(_) => return result,
+    };
+
+    let mut probes = tokio::task::JoinSet::new();
+    for _ in 0..concurrency {
+        let client = client.clone();
+        let accept_status = options.accept_status.clone();
+        let latency_url = options.latency_url.clone();
+        let limit = options
+            .request_timeout(Duration::from_secs(10))
+            .unwrap_or_default();
+        probes.spawn(async move {
+            let mut latencies = Vec::new();
+            for _ in 0..PROBES_PER_TASK {
+                let start = Instant::now();
+                match timeout(limit, client.head(&latency_url).send()).await {
+                    Ok(Ok(response)) if accept_status.contains(response.status()) => {
+                        latencies.push(start.elapsed().as_micros() as f64 / 1000.0);
+                    }
+                    _ => {}
+                }
+            }
+            latencies
+        });
+    }
+    let mut latencies: Vec<f64> = probes.join_all().await.into_iter().flatten().collect();
+    result.probes_ok = latencies.len();
+    if !latencies.is_empty() {
+        latencies.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+        result.median_latency = Some(median(&latencies));
+    }
+
+    if let Some(size) = options.download_size {
+        let url = download_url(size);
+        let start = Instant::now();
+        let mut downloads = tokio::task::JoinSet::new();
+        for _ in 0..concurrency {
+            let request = client.get(&url);
+            let retry = options.retry_policy();
+            downloads.spawn(async move {
+                let response =
+                    match send_with_retry(request, DOWNLOAD_RESPONSE_TIMEOUT, retry).await {
+                        (Ok(Ok(response)), _) if response.status().is_success() => response,
+                        (Ok(Ok(response)), _) => {
+                            let status = response.status().to_string();
+                            return Err(SpeedResult::failed(FailReason::Http, status));
+                        }
+                        (failed, _) => {
+                            return Err(request_failure(&failed, DOWNLOAD_RESPONSE_TIMEOUT));
+                        }
+                    };
+                drain_body(response).await.map_err(|e| {
+                    SpeedResult::failed(
+                        FailReason::Stream,
+                        format!("Failed to read response: {}", e),
+                    )
+                })
+            });
+        }
+        // 任何一路下载失败时，剩下几路的合计速度没有意义，报告失败原因
+        let mut bytes = 0;
+        let mut failure = None;
+        for download in downloads.join_all().await {
+            match download {
+                Ok(received) => bytes += received,
+                Err(failed) => failure = failure.or(Some(failed)),
+            }
+        }
+        result.aggregate_speed = Some(
+            failure
+                .unwrap_or_else(|| SpeedResult::Success(throughput_mbps(bytes, start.elapsed()))),
+        );
+    }
+
+    result
+}
+
+/// Print per-attempt samples as an ASCII histogram with `bucket_ms`-wide buckets
+fn print_histogram(samples: &[f64], bucket_ms: f64) {
+    const MAX_BUCKETS: usize = 30;
+    const MAX_BAR: usize = 40;
+
+    let valid: Vec<f64> = samples.iter().copied().filter(|l| l.is_finite()).collect();
+    let failed = samples.len() - valid.len();
+    if valid.is_empty() && failed == 0 {
+        return;
+    }
+
+    // 从最小样本所在的格子开始，中间的空格子也画出来，双峰才看得出
+    let first = valid.iter().fold(f64::INFINITY, |a, &b| a.min(b));
+    let first = if valid.is_empty() {
+        0
+    } else {
+        (first / bucket_ms) as usize
+    };
+    let mut counts = Vec::new();
+    for &latency in &valid {
+        let idx = ((latency / bucket_ms) as usize - first).min(MAX_BUCKETS - 1);
+        if counts.len() <= idx {
+            counts.resize(idx + 1, 0);
+        }
+        counts[idx] += 1;
+    }
+
+    let peak = counts.iter().copied().chain([failed]).max().unwrap_or(0);
+    let bar = |n: usize| "█".repeat((n * MAX_BAR).div_ceil(peak.max(MAX_BAR)).max(1));
+    // 格子边界去掉多余的 0，避免 0.2 的倍数显示成 1.2000000000000002
+    let edge = |ms: f64| {
+        let text = format!("{ms:.3}");
+        text.trim_end_matches('0').trim_end_matches('.').to_string()
+    };
+    outln!("  延迟分布 (每格 {} ms):", edge(bucket_ms));
+    for (idx, &n) in counts.iter().enumerate() {
+        let low = (first + idx) as f64 * bucket_ms;
+        let label = if idx == MAX_BUCKETS - 1 {
+            format!("≥{}", edge(low))
+        } else {
+            format!("{}-{}", edge(low), edge(low + bucket_ms))
+        };
+        if n == 0 {
+            outln!("    {} ms │", pad_start(&label, 12));
+        } else {
+            outln!("    {} ms │{} {n}", pad_start(&label, 12), bar(n));
+        }
+    }
+    if failed > 0 {
+        outln!("    {}    │{} {failed}", pad_start("失败", 12), bar(failed));
+    }
+}
+
+fn print_latency(latency: &LatencyResult) {
+    match latency {
+        LatencyResult::Success {
+            median,
+            average,
+            minimum,
+            maximum,
+            trimmed_mean,
+            ci,
+        } => {
+            out!(
+                "✅ {}/{}/{}/{} ms",
+                decimal(*median),
+                decimal(*average),
+                decimal(*minimum),
+                decimal(*maximum)
+            );
+            if let Some(ci) = ci {
+                out!(" (均值 ±{} ms, 95%)", decimal(*ci));
+            }
+            match trimmed_mean {
+                Some(trimmed) => outln!(" (截尾均值 {} ms)", decimal(*trimmed)),
+                None => outln!(),
+            }
+        }
+        LatencyResult::Unstable(valid, total) => {
+            outln!("⚠️  不稳定 ({}/{} 次成功)", valid, total);
+        }
+        LatencyResult::AllFailed => {
+            outln!("❌ 全部失败");
+        }
+        LatencyResult::SessionError(err) => {
+            outln!("❌ 连接错误: {}", err);
+        }
+        LatencyResult::TlsError(err) => {
+            outln!("🔒 TLS 错误: {}", err);
+            if !ACCEPT_INVALID_CERTS.load(Ordering::Relaxed) {
+                outln!("  ↳ 节点可能在做中间人；如需继续测试可加 --danger-accept-invalid-certs");
+            }
+        }
+    }
+}
+
+/// Size requested for the background download saturating the link; it is
+/// aborted as soon as the latency probes are done
+const SATURATE_DOWNLOAD_BYTES: u64 = 1 << 30;
+
+/// Measure latency while a background download keeps the node busy (bufferbloat)
+async fn test_node_loaded_latency(port: u16, options: &TestOptions) -> LatencyResult {
+    let saturate = tokio::spawn(async move {
+        let client = Proxy::all(proxy_url(port))
+            .and_then(|proxy| test_client_builder().proxy(proxy).build());
+        if let Ok(client) = client {
+            let url = download_url(SATURATE_DOWNLOAD_BYTES);
+            if let Ok(response) = client.get(url).send().await {
+                let _ = drain_body(response).await;
+            }
+        }
+    });
+    // 给下载一点时间越过慢启动，让链路真正进入满载状态
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let loaded = match &options.tcp_ping {
+        Some(target) => test_node_tcp_ping(port, target, options).await.0,
+        None => test_node_latency(port, options).await.0,
+    };
+    saturate.abort();
+    loaded
+}
+
+/// Speed result, every download's Mbps (`--speed-samples`), the `-d` sweep and the
+/// peak sustained speed; all empty when the speed test is off or deferred
+type SpeedProbe = (Option<SpeedResult>, Vec<f64>, Vec<SweepPoint>, Option<f64>);
+
+/// The speed part of `test_node`, also run on its own by the split speed phase
+async fn probe_node_speed(port: u16, options: &TestOptions) -> SpeedProbe {
+    let Some(size) = options.download_size.filter(|_| !options.defer_speed) else {
+        return (None, Vec::new(), Vec::new(), None);
+    };
+    outln!("  速度测试:");
+    // 规模扫描先从小到大下载，预热只做在第一次下载前
+    let mut sweep = Vec::new();
+    for (i, &bytes) in options.sweep_sizes.iter().enumerate() {
+        let download = test_node_speed(port, bytes, options.speed_warmup && i == 0, options);
+        let speed = match options.remaining_budget() {
+            None => download.await.0,
+            Some(left) => timeout(left, download).await.map_or_else(
+                |_| SpeedResult::failed(FailReason::Timeout, "节点时间预算用尽"),
+                |(speed, _)| speed,
+            ),
+        };
+        sweep.push(SweepPoint { bytes, speed });
+    }
+    let warmup = options.speed_warmup && sweep.is_empty();
+    let speed_test = async {
+        let mut size = size;
+        let (mut result, mut peak) = test_node_speed(port, size, warmup, options).await;
+        if let Some(min_time) = options.min_download_time {
+            // 下载太快时测量窗口过短，翻倍重测直到用时达到下限
+            while let SpeedResult::Success(mbps) = result {
+                // 空响应的速度为 0，此时再增大下载量也没有意义
+                if mbps <= 0.0 {
+                    break;
+                }
+                let seconds = size as f64 * 8.0 / 1_000_000.0 / mbps;
+                if seconds >= min_time.as_secs_f64() || size >= MAX_AUTO_DOWNLOAD_BYTES {
+                    break;
+                }
+                size = (size * 2).min(MAX_AUTO_DOWNLOAD_BYTES);
+                outln!(
+                    "  ↳ 用时 {seconds:.2}s 不足 {:.2}s，增大到 {} 重测",
+                    min_time.as_secs_f64(),
+                    format_bytes(size)
+                );
+                (result, peak) = test_node_speed(port, size, false, options).await;
+            }
+            outln!("  ↳ 最终下载大小: {}", format_bytes(size));
+        }
+        // 已经因为太慢被提前停止，再下载几次也不会更快
+        if options.speed_samples <= 1 || matches!(result, SpeedResult::TooSlow(_)) {
+            return (result, Vec::new(), peak, size);
+        }
+
+        // 之后的每次下载沿用确定下来的大小，失败的轮次不计入样本
+        let mut samples = Vec::new();
+        if let SpeedResult::Success(mbps) = result {
+            samples.push(mbps);
+        }
+        for round in 2..=options.speed_samples {
+            outln!("  第 {}/{} 次下载:", round, options.speed_samples);
+            match test_node_speed(port, size, false, options).await {
+                (SpeedResult::Success(mbps), round_peak) => {
+                    samples.push(mbps);
+                    // 多次下载时取各次中最高的窗口速度
+                    peak = peak.into_iter().chain(round_peak).reduce(f64::max);
+                }
+                (failed, _) => outln!("  ↳ 本次下载失败: {}", failed),
+            }
+        }
+        if samples.is_empty() {
+            return (result, samples, peak, size);
+        }
+        let mut sorted = samples.clone();
+        sorted.sort_unstable_by(|a, b| a.total_cmp(b));
+        let median = median(&sorted);
+        outln!(
+            "  ↳ {}/{} 次下载成功，中位数 {}，最佳 {}",
+            samples.len(),
+            options.speed_samples,
+            SpeedResult::Success(median),
+            SpeedResult::Success(*sorted.last().unwrap())
+        );
+        (SpeedResult::Success(median), samples, peak, size)
+    };
+    // --min-download-time 可能增大了下载量，扫描的最后一点记录实际的大小
+    let (speed_result, downloads, peak_speed, size) = match options.remaining_budget() {
+        None => speed_test.await,
+        Some(left) => timeout(left, speed_test).await.unwrap_or_else(|_| {
+            (
+                SpeedResult::failed(FailReason::Timeout, "节点时间预算用尽"),
+                Vec::new(),
+                None,
+                size,
+            )
+        }),
+    };
+    let speed_result = match (speed_result, options.speed_floor) {
+        (SpeedResult::Success(mbps), Some(floor)) if mbps < floor => SpeedResult::TooSlow(mbps),
+        (speed_result, _) => speed_result,
+    };
+
+    match &speed_result {
+        SpeedResult::Success(_) => {
+            outln!("  ✅ 下载速度: {}", speed_result);
+        }
+        SpeedResult::TooSlow(_) => {
+            outln!("  ⚠️  下载速度低于 --speed-floor: {}", speed_result);
+        }
+        SpeedResult::Failed { reason, detail } => {
+            outln!("  ❌ 速度测试失败 ({}): {}", reason, detail);
+        }
+    }
+    if !sweep.is_empty() {
+        sweep.push(SweepPoint {
+            bytes: size,
+            speed: speed_result.clone(),
+        });
+        let points = sweep
+            .iter()
+            .map(|point| match &point.speed {
+                SpeedResult::Failed { reason, .. } => {
+                    format!("{} → {}", format_bytes(point.bytes), reason)
+                }
+                speed => format!("{} → {}", format_bytes(point.bytes), speed),
+            })
+            .collect::<Vec<_>>();
+        outln!("  ↳ 规模扫描: {}", points.join(" | "));
+    }
+    (Some(speed_result), downloads, sweep, peak_speed)
+}
+
+async fn test_node(tag: &str, port: u16, options: &TestOptions) -> NodeResult {
+    let own_target = options.for_node(tag);
+    let options = own_target.as_ref().unwrap_or(options);
+    let budgeted = options.budgeted();
+    let options = budgeted.as_ref().unwrap_or(options);
+
+    let latency_probe = move || async move {
+        out!("  延迟测试: ");
+        let (latency, samples, http_error, failure) = match &options.tcp_ping {
+            Some(target) => {
+                let (latency, samples, failure) = test_node_tcp_ping(port, target, options).await;
+                (latency, samples, None, failure)
+            }
+            None => test_node_latency(port, options).await,
+        };
+
+        print_latency(&latency);
+        if let Some(bucket_ms) = options.histogram_bucket {
+            print_histogram(&samples, bucket_ms);
+        }
+        (latency, samples, http_error, failure)
+    };
+    let speed_probe = move || probe_node_speed(port, options);
+
+    // 同时进行时延迟是在下载过程中测得的，结果更接近负载下延迟
+    let (latency_probed, overlapped) =
+        if options.overlap_probes && options.download_size.is_some() && !options.defer_speed {
+            outln!("  延迟与速度测试同时进行 (--overlap-probes)");
+            let (latency, speed) = tokio::join!(latency_probe(), speed_probe());
+            (latency, Some(speed))
+        } else {
+            (latency_probe().await, None)
+        };
+    let (latency, samples, http_error, latency_failure) = latency_probed;
+
+    // 空闲延迟都测不通时，负载下测试没有意义
+    let loaded_latency = if options.latency_under_load
+        && matches!(latency, LatencyResult::Success { .. })
+        && !options.budget_exhausted()
+    {
+        out!("  负载下延迟测试: ");
+        let loaded = test_node_loaded_latency(port, options).await;
+        print_latency(&loaded);
+        if let (
+            LatencyResult::Success { median: idle, .. },
+            LatencyResult::Success { median: busy, .. },
+        ) = (&latency, &loaded)
+        {
+            outln!("  ↳ 负载下延迟增加: {} ms", signed_decimal(busy - idle));
+        }
+        Some(loaded)
+    } else {
+        None
+    };
+
+    let (speed, speed_samples, sweep, peak_speed) = match overlapped {
+        Some(speed) => speed,
+        None => speed_probe().await,
+    };
+
+    if !options.load_levels.is_empty() && !options.budget_exhausted() {
+        outln!("  负载测试:");
+        const WIDTHS: [usize; 3] = [8, 12, 10];
+        let line = |cells: [&str; 4]| {
+            let padded: Vec<String> = cells
+                .iter()
+                .zip(WIDTHS)
+                .map(|(cell, width)| pad_end(cell, width))
+                .collect();
+            format!("    {} {}", padded.join(" "), cells[3])
+        };
+        outln!("{}", line(["并发", "成功", "med", "聚合速度"]));
+        for &concurrency in &options.load_levels {
+            let level = test_node_load(port, concurrency, options).await;
+            let median = level
+                .median_latency
+                .map(decimal)
+                .unwrap_or_else(|| "-".to_string());
+            let speed = match &level.aggregate_speed {
+                Some(SpeedResult::Failed { reason, detail }) => format!("❌ {reason}: {detail}"),
+                Some(speed) => speed.to_string(),
+                None => "-".to_string(),
+            };
+            let concurrency = level.concurrency.to_string();
+            let probes = format!("{}/{}", level.probes_ok, level.probes_total);
+            outln!("{}", line([&concurrency, &probes, &median, &speed]));
+        }
+    }
+
+    let mut dns_ms = None;
+    if options.dns_probe {
+        match test_node_dns(port).await {
+            Ok(ms) => {
+                outln!("  DNS 解析 (代理端): {} ms", decimal(ms));
+                dns_ms = Some(ms);
+            }
+            Err(e) => outln!("  ❌ DNS 测试失败: {}", e),
+        }
+    }
+
+    // 节点本身不通时任何请求都会失败，不能据此判断为不支持 IPv6
+    let mut ipv6_ok = None;
+    if options.check_ipv6 && matches!(latency, LatencyResult::Success { .. }) {
+        match test_node_ipv6(port).await {
+            Ok(ok) => {
+                outln!("  IPv6: {}", if ok { "可达" } else { "不可达" });
+                ipv6_ok = Some(ok);
+            }
+            Err(e) => outln!("  ❌ IPv6 检测失败: {}", e),
+        }
+    }
+
+    let tls = options
+        .show_tls
+        .then(|| test_node_tls(port, &options.latency_url));
+    let tls = match tls {
+        Some(check) => {
+            let check = check.await;
+            match &check {
+                TlsCheck::Negotiated { .. } => outln!("  TLS: {}", check),
+                TlsCheck::Failed(e) => outln!("  ⚠️  TLS 握手失败: {}", e),
+            }
+            Some(check)
+        }
+        None => None,
+    };
+
+    let mut exit_ip = None;
+    let mut country = None;
+    let mut leak_suspected = false;
+    if options.direct_ip.is_some() || options.detect_country {
+        match fetch_trace(TraceRoute::Node(port)).await {
+            Ok(trace) => {
+                exit_ip = trace.get("ip").cloned();
+                country = trace.get("loc").cloned();
+                leak_suspected = options.direct_ip.is_some() && exit_ip == options.direct_ip;
+                if options.detect_country {
+                    outln!("  出口国家: {}", country.as_deref().unwrap_or("未知"));
+                }
+                match &exit_ip {
+                    Some(ip) if leak_suspected => {
+                        outln!("  ⚠️  LEAK? 出口 IP {} 与直连 IP 相同", ip);
+                    }
+                    Some(ip) => outln!("  出口 IP: {}", ip),
+                    None => outln!("  ⚠️  trace 响应中没有 ip 字段"),
+                }
+            }
+            Err(e) => outln!("  ❌ 获取出口 IP 失败: {}", e),
+        }
+    }
+
+    NodeResult {
+        tag: tag.to_string(),
+        port,
+        latency,
+        samples: samples
+            .into_iter()
+            .map(|sample| sample.is_finite().then_some(sample))
+            .collect(),
+        speed,
+        loaded_latency,
+        latency_failure,
+        exit_ip,
+        country,
+        leak_suspected,
+        dns_ms,
+        ipv6_ok,
+        speed_samples,
+        http_error,
+        tls,
+        sweep,
+        peak_speed,
+    }
+}
+
+/// Ranking order of two results: fastest download first when `by_speed`, then lowest
+/// median latency, finally the tag so that ties always come out the same way
+fn rank_order(a: &NodeResult, b: &NodeResult, by_speed: bool) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+
+    // 低于 --speed-floor 的结果与失败同等对待，交给延迟排序
+    let speed = || match (&a.speed, &b.speed) {
+        (Some(SpeedResult::Success(sa)), Some(SpeedResult::Success(sb))) => {
+            sb.partial_cmp(sa).unwrap_or(Ordering::Equal)
+        }
+        (Some(SpeedResult::Success(_)), _) => Ordering::Less,
+        (_, Some(SpeedResult::Success(_))) => Ordering::Greater,
+        _ => Ordering::Equal,
+    };
+    let latency = || match (&a.latency, &b.latency) {
+        (LatencyResult::Success { median: ma, .. }, LatencyResult::Success { median: mb, .. }) => {
+            ma.partial_cmp(mb).unwrap_or(Ordering::Equal)
+        }
+        (LatencyResult::Success { .. }, _) => Ordering::Less,
+        (_, LatencyResult::Success { .. }) => Ordering::Greater,
+        _ => Ordering::Equal,
+    };
+
+    let primary = if by_speed { speed() } else { Ordering::Equal };
+    primary.then_with(latency).then_with(|| a.tag.cmp(&b.tag))
+}
+
+/// Everything a checkpoint's results depend on; resuming requires an exact match
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct RunParams {
+    config_path: String,
+    nodes: Vec<(String, u16)>,
+    download_size: Option<u64>,
+    accept_status: String,
+    #[serde(default)]
+    speed_warmup: bool,
+    #[serde(default)]
+    no_warmup_latency: bool,
+    #[serde(default)]
+    tcp_ping: Option<String>,
+    #[serde(default)]
+    latency_under_load: bool,
+    #[serde(default = "default_latency_count")]
+    latency_count: usize,
+    #[serde(default = "default_min_valid")]
+    min_valid: usize,
+    #[serde(default)]
+    trim_fraction: Option<f64>,
+    #[serde(default)]
+    confidence_interval: bool,
+    #[serde(default)]
+    node_budget: Option<f64>,
+    #[serde(default)]
+    min_download_time: Option<f64>,
+    #[serde(default)]
+    reuse: Option<usize>,
+    #[serde(default)]
+    detect_country: bool,
+    #[serde(default = "default_latency_url")]
+    latency_url: String,
+    #[serde(default)]
+    respect_node_target: bool,
+    #[serde(default)]
+    think_time: Option<u64>,
+    #[serde(default)]
+    dns_probe: bool,
+    #[serde(default)]
+    check_ipv6: bool,
+    #[serde(default = "default_speed_samples")]
+    speed_samples: usize,
+    #[serde(default)]
+    measure_error_latency: bool,
+    #[serde(default)]
+    timeout_as_slow: bool,
+    #[serde(default)]
+    speed_floor: Option<f64>,
+    #[serde(default)]
+    abort_below_floor: bool,
+    #[serde(default)]
+    overlap_probes: bool,
+    #[serde(default)]
+    sweep_sizes: Vec<u64>,
+    #[serde(default)]
+    segments: Option<usize>,
+    #[serde(default)]
+    user_agent: Option<String>,
+    #[serde(default)]
+    danger_accept_invalid_certs: bool,
+    #[serde(default)]
+    user_index: usize,
+    #[serde(default)]
+    leak_check: bool,
+    #[serde(default)]
+    show_tls: bool,
+    #[serde(default)]
+    honor_retry_after: bool,
+    #[serde(default)]
+    retry_transient: bool,
+    #[serde(default)]
+    jitter: bool,
+}
+
+// 旧断点文件没有这些字段，按当时的固定值处理
+fn default_latency_count() -> usize {
+    10
+}
+
+fn default_min_valid() -> usize {
+    3
+}
+
+fn default_speed_samples() -> usize {
+    1
+}
+
+fn default_latency_url() -> String {
+    LATENCY_URL.to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Checkpoint {
+    params: RunParams,
+    results: Vec<NodeResult>,
+}
+
+impl Checkpoint {
+    /// Load an existing checkpoint, or start an empty one if the file does not exist yet
+    fn load(path: &str, params: RunParams) -> Result<Self, String> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Checkpoint {
+                    params,
+                    results: Vec::new(),
+                })
+            }
+            Err(e) => return Err(format!("无法读取断点文件: {}", e)),
+        };
+
+        let checkpoint: Checkpoint =
+            serde_json::from_str(&content).map_err(|e| format!("断点文件解析失败: {}", e))?;
+        if checkpoint.params != params {
+            return Err("断点文件与当前配置/测试参数不匹配，请删除该文件或更换路径".to_string());
+        }
+        Ok(checkpoint)
+    }
+
+    fn save(&self, path: &str) -> std::io::Result<()> {
+        // 先写临时文件再重命名，避免中断时留下损坏的断点
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(tmp_path, path)
+    }
+
+    fn record(&mut self, path: &str, result: &NodeResult) {
+        match self
+            .results
+            .iter_mut()
+            .find(|r| r.tag == result.tag && r.port == result.port)
+        {
+            Some(existing) => *existing = result.clone(),
+            None => self.results.push(result.clone()),
+        }
+        if let Err(e) = self.save(path) {
+            errln!("  ⚠️  无法写入断点文件: {}", e);
+        }
+    }
+}
+
+/// Download the config body for `--config-url`, sending each `NAME: VALUE` header
+async fn fetch_config(url: &str, headers: &[String]) -> Result<String, String> {
+    let client = Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut request = client.get(url);
+    for header in headers {
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(format!("请求头格式应为 NAME: VALUE: {header}"));
+        };
+        request = request.header(name.trim(), value.trim());
+    }
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("HTTP {}", response.status()));
+    }
+    response.text().await.map_err(|e| e.to_string())
+}
+
+/// SingBox's Clash API controller, used by `--clash-api` to switch a selector's outbound
+struct ClashApi {
+    client: Client,
+    url: reqwest::Url,
+    secret: Option<String>,
+    selector: String,
+}
+
+#[derive(Deserialize)]
+struct ClashSelector {
+    now: String,
+    all: Vec<String>,
+}
+
+impl ClashApi {
+    fn new(url: &str, secret: Option<String>, selector: String) -> Result<Self, String> {
+        let url = reqwest::Url::parse(url).map_err(|e| format!("无效的 URL {url}: {e}"))?;
+        if url.cannot_be_a_base() {
+            return Err(format!("无效的 URL {url}"));
+        }
+        let client = Client::builder()
+            .no_proxy()
+            .timeout(Duration::from_secs(5))
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(ClashApi {
+            client,
+            url,
+            secret,
+            selector,
+        })
+    }
+
+    /// `/proxies/<selector>`, with the tag percent-encoded
+    fn selector_url(&self) -> reqwest::Url {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .expect("checked in ClashApi::new")
+            .pop_if_empty()
+            .extend(["proxies", &self.selector]);
+        url
+    }
+
+    fn request(&self, method: reqwest::Method) -> reqwest::RequestBuilder {
+        let request = self.client.request(method, self.selector_url());
+        match &self.secret {
+            Some(secret) => request.bearer_auth(secret),
+            None => request,
+        }
+    }
+
+    /// The selector's outbounds and the one currently selected
+    async fn members(&self) -> Result<ClashSelector, String> {
+        let response = self
+            .request(reqwest::Method::GET)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("HTTP {}", response.status()));
+        }
+        response
+            .json()
+            .await
+            .map_err(|e| format!("{} 不是 selector: {}", self.selector, e))
+    }
+
+    async fn select(&self, outbound: &str) -> Result<(), String> {
+        let response = self
+            .request(reqwest::Method::PUT)
+            .json(&serde_json::json!({ "name": outbound }))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("HTTP {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// `test_node`, first switching the `--clash-api` selector to `tag` when there is one
+async fn test_node_via(
+    clash: Option<&ClashApi>,
+    tag: &str,
+    port: u16,
+    options: &TestOptions,
+) -> NodeResult {
+    if let Some(clash) = clash {
+        if let Err(e) = clash.select(tag).await {
+            // 切换失败时测到的是其它出站，不能当作该节点的结果
+            outln!("  ❌ 无法通过 Clash API 切换到该出站: {}", e);
+            return NodeResult::failed(
+                tag,
+                port,
+                LatencyResult::SessionError(format!("Clash API: {e}")),
+            );
+        }
+    }
+    test_node(tag, port, options).await
+}
+
+const ON_RESULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Run the `--on-result` command for one finished node; failures never abort the run
+async fn run_result_hook(command: &str, result: &NodeResult) {
+    let (status, median) = match &result.latency {
+        LatencyResult::Success { median, .. } => ("success".to_string(), format!("{median:.2}")),
+        other => (other.to_string(), String::new()),
+    };
+    let speed = match &result.speed {
+        Some(SpeedResult::Success(mbps)) => format!("{mbps:.2}"),
+        _ => String::new(),
+    };
+
+    let mut hook = if cfg!(windows) {
+        let mut hook = tokio::process::Command::new("cmd");
+        hook.arg("/C");
+        hook
+    } else {
+        let mut hook = tokio::process::Command::new("sh");
+        hook.arg("-c");
+        hook
+    };
+    hook.arg(command)
+        .env("TAG", &result.tag)
+        .env("PORT", result.port.to_string())
+        .env("MEDIAN", median)
+        .env("SPEED", speed)
+        .env("STATUS", status)
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true);
+    // 结果流占用 stdout 时，钩子的输出改走 stderr
+    if CONSOLE_TO_STDERR.load(Ordering::Relaxed) {
+        hook.stdout(std::io::stderr());
+    }
+
+    match timeout(ON_RESULT_TIMEOUT, hook.status()).await {
+        Ok(Ok(status)) if status.success() => {}
+        Ok(Ok(status)) => errln!(
+            "⚠️  --on-result 钩子退出状态异常 ({}): {}",
+            result.tag,
+            status
+        ),
+        Ok(Err(e)) => errln!("⚠️  --on-result 钩子无法启动: {}", e),
+        Err(_) => errln!(
+            "⚠️  --on-result 钩子超过 {}s 未结束，已终止 ({})",
+            ON_RESULT_TIMEOUT.as_secs(),
+            result.tag
+        ),
+    }
+}
+
+/// Emit one `--format ndjson` line and flush it so consumers see it right away
+fn write_ndjson(sink: &mut Option<Box<dyn std::io::Write>>, result: &NodeResult) {
+    use std::io::Write;
+
+    let Some(writer) = sink else {
+        return;
+    };
+    let line = serde_json::to_string(result).expect("results are always serializable");
+    if let Err(e) = writeln!(writer, "{line}").and_then(|_| writer.flush()) {
+        errln!("❌ 写入 ndjson 失败: {}", e);
+        *sink = None;
+    }
+}
+
+/// Run `task` on every item, at most `limit` at a time; `on_done` sees each result
+/// with its item index as soon as it finishes, and all come back in item order
+async fn run_concurrently<T, R, F, Fut>(
+    items: Vec<T>,
+    limit: usize,
+    ramp: Option<Duration>,
+    task: F,
+    mut on_done: impl AsyncFnMut(usize, &R),
+) -> Vec<R>
+where
+    F: Fn(T) -> Fut,
+    Fut: std::future::Future<Output = R> + Send + 'static,
+    R: Send + 'static,
+{
+    let mut running = tokio::task::JoinSet::new();
+    let mut results: Vec<Option<R>> = std::iter::repeat_with(|| None).take(items.len()).collect();
+    for (idx, item) in items.into_iter().enumerate() {
+        if running.len() >= limit {
+            if let Some(joined) = running.join_next().await {
+                let (idx, result) = joined.expect("test tasks don't panic");
+                on_done(idx, &result).await;
+                results[idx] = Some(result);
+            }
+        }
+        let future = task(item);
+        // 只有最初的一批同时启动，把它们分散到 ramp 内各自的时间片中随机开始；
+        // 之后的任务在前面的完成时才启动，本来就是错开的
+        let delay = ramp.filter(|_| idx < limit).map(|ramp| {
+            let slot = ramp / limit as u32;
+            slot * idx as u32 + random_up_to(slot)
+        });
+        running.spawn(async move {
+            if let Some(delay) = delay {
+                tokio::time::sleep(delay).await;
+            }
+            (idx, future.await)
+        });
+    }
+    while let Some(joined) = running.join_next().await {
+        let (idx, result) = joined.expect("test tasks don't panic");
+        on_done(idx, &result).await;
+        results[idx] = Some(result);
+    }
+    results.into_iter().flatten().collect()
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m{:02}s", secs / 3600, secs % 3600 / 60, secs % 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{:.1}s", duration.as_secs_f64())
+    }
+}
+
+/// Serve empty `200 OK` responses on a keep-alive connection until the client hangs up
+async fn serve_mock_http(mut stream: tokio::net::TcpStream) -> std::io::Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut pending = Vec::new();
+    let mut buf = [0u8; 1024];
+    loop {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        pending.extend_from_slice(&buf[..n]);
+        // 请求都不带 body，每遇到一个空行就是一个完整请求
+        while let Some(end) = pending.windows(4).position(|w| w == b"\r\n\r\n") {
+            pending.drain(..end + 4);
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .await?;
+        }
+    }
+}
+
+/// `--self-bench`: run the latency probe against an in-process server and check the stats math
+///
+/// The server also stands in for the proxy, so the measured latency is the tool's own overhead
+async fn self_bench_run(latency_count: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve_mock_http(stream));
+        }
+    });
+    // 明文 URL 经 HTTP 代理时请求直接发给代理，由 mock 服务器回应
+    let _ = DETECTED_SCHEMES.set(HashMap::from([(port, "http")]));
+
+    let options = TestOptions {
+        latency_url: format!("http://127.0.0.1:{port}/"),
+        latency_count,
+        min_valid: 1,
+        accept_status: "200-299".parse()?,
+        ..TestOptions::default()
+    };
+    outln!("🧪 本机 mock 服务器延迟 ({} 次):", latency_count);
+    let (latency, ..) = test_node_latency(port, &options).await;
+    print_latency(&latency);
+
+    // 已知样本的统计结果应与手算一致
+    let samples = [4.0, 1.0, 3.0, f64::INFINITY, 2.0, 100.0];
+    let options = TestOptions {
+        min_valid: 1,
+        trim_fraction: Some(0.2),
+        ..TestOptions::default()
+    };
+    let stats_ok = matches!(
+        summarize_latencies(&samples, samples.len(), &options),
+        LatencyResult::Success {
+            median,
+            average,
+            minimum,
+            maximum,
+            trimmed_mean: Some(trimmed),
+            ..
+        } if median == 3.0 && average == 22.0 && minimum == 1.0 && maximum == 100.0 && trimmed == 3.0
+    );
+    if stats_ok {
+        outln!("✅ 统计计算校验通过");
+    } else {
+        outln!("❌ 统计计算校验失败");
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let Args {
+        config_path,
+        profile,
+        config_url,
+        socks,
+        config_header,
+        clash_api,
+        clash_selector,
+        clash_inbound,
+        download_size,
+        download_mb,
+        whitelist_patterns,
+        blacklist_patterns,
+        accept_status,
+        retry_unstable,
+        speed_unit,
+        load_test,
+        latency_concurrency,
+        speed_concurrency,
+        ramp,
+        resume,
+        speed_warmup,
+        no_warmup_speed,
+        no_retry_unstable,
+        no_latency_under_load,
+        no_warmup_latency,
+        report_warmup,
+        strict_json,
+        grade,
+        grade_latency,
+        grade_speed,
+        tcp_ping,
+        probe_host,
+        stream_results,
+        latency_count,
+        min_valid,
+        min_valid_ratio,
+        latency_under_load,
+        format,
+        columns,
+        output_dir,
+        failures_output,
+        table_style,
+        leak_check,
+        local_address,
+        top,
+        robust,
+        ci,
+        trim_fraction,
+        precision,
+        tags_stdin,
+        tags_literal,
+        node_budget,
+        min_download_time,
+        reuse,
+        country: country_filter,
+        self_bench,
+        histogram,
+        histogram_bucket,
+        latency_url,
+        danger_allow_http,
+        respect_node_target,
+        danger_accept_invalid_certs,
+        dns_cache,
+        honor_retry_after,
+        retry_transient,
+        no_sort,
+        failures_first,
+        export_sort,
+        autodetect_protocol,
+        user_index,
+        summary_only,
+        think_time,
+        confirm_over,
+        yes,
+        dns_probe,
+        check_ipv6,
+        user_agent,
+        select,
+        speed_samples,
+        measure_error_latency,
+        timeout_as_slow,
+        on_result,
+        group_by,
+        alias_file,
+        speed_floor,
+        abort_below_floor,
+        overlap_probes,
+        segments,
+        retry_budget,
+        show_tls,
+        baseline,
+        retest_top,
+        min_nodes,
+        only_changed,
+        health_file,
+        ewma_alpha,
+        no_emoji,
+        jitter,
+        print_config,
+        locale,
+    } = Args::parse();
+
+    // 多个格式直接拼在 stdout 上无法区分，只能分别写入文件
+    if output_dir.is_none() && format.iter().filter(|f| !f.is_console()).count() > 1 {
+        errln!("❌ 同时输出多种格式时需要 --output-dir");
+        return Ok(());
+    }
+    if output_dir.is_none() && format.iter().any(|&f| !f.is_console()) {
+        CONSOLE_TO_STDERR.store(true, Ordering::Relaxed);
+    }
+
+    CONSOLE_MUTED.store(summary_only, Ordering::Relaxed);
+    let plain_symbols = no_emoji || !console_supports_unicode();
+    PLAIN_SYMBOLS.store(plain_symbols, Ordering::Relaxed);
+    let table_style = match table_style {
+        TableStyle::Unicode if plain_symbols => TableStyle::Ascii,
+        style => style,
+    };
+    let _ = SPEED_UNIT.set(speed_unit);
+    let _ = PRECISION.set(precision);
+    if let Some(locale) = locale {
+        let _ = NUMBER_LOCALE.set(locale);
+    }
+
+    // 显式给出的参数优先于 --profile 的预设
+    let preset = profile.map_or(Preset::DEFAULT, Profile::preset);
+    let latency_count = latency_count.unwrap_or(preset.latency_count);
+    let speed_samples = speed_samples.unwrap_or(preset.speed_samples);
+    // --clash-api 不能并发测试，预设的并发度不适用
+    let latency_concurrency = latency_concurrency.unwrap_or(if clash_api.is_some() {
+        1
+    } else {
+        preset.latency_concurrency
+    });
+    let speed_concurrency = speed_concurrency.unwrap_or(1);
+    let speed_warmup = flag_override(speed_warmup, no_warmup_speed).unwrap_or(preset.speed_warmup);
+    let retry_unstable =
+        flag_override(retry_unstable, no_retry_unstable).unwrap_or(preset.retry_unstable);
+    let latency_under_load = flag_override(latency_under_load, no_latency_under_load)
+        .unwrap_or(preset.latency_under_load);
+    let load_test = if load_test.is_empty() {
+        preset.load_test.to_vec()
+    } else {
+        load_test
+    };
+    if let Some(user_agent) = user_agent {
+        let _ = USER_AGENT.set(user_agent);
+    }
+    ACCEPT_INVALID_CERTS.store(danger_accept_invalid_certs, Ordering::Relaxed);
+    if dns_cache == DnsCache::On {
+        let _ = DNS_CACHE.set(Default::default());
+    }
+
+    if self_bench {
+        return self_bench_run(latency_count).await;
+    }
+
+    if !config_header.is_empty() && config_url.is_none() {
+        errln!("⚠️  --config-header 只对 --config-url 生效");
+    }
+    if !socks.is_empty() && config_path.is_some() {
+        errln!("❌ --socks 与配置文件不能同时使用");
+        return Ok(());
+    }
+    // 使用 --config-url 时，config_path 记录的是 URL，使用 --socks 时是代理列表
+    //（断点文件也按它校验）
+    let ad_hoc = (!socks.is_empty()).then(|| {
+        socks
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(",")
+    });
+    let Some(config_path) = ad_hoc
+        .or_else(|| config_url.clone())
+        .or(config_path)
+        .or_else(|| std::env::var(CONFIG_PATH_ENV).ok())
+    else {
+        errln!("❌ 未指定配置文件路径，请传入 CONFIG_PATH 或设置 {CONFIG_PATH_ENV} 环境变量");
+        return Ok(());
+    };
+
+    let mut download_sizes: Vec<u64> = if !download_size.is_empty() {
+        download_size
+            .into_iter()
+            .map(|ByteSize(bytes)| bytes)
+            .collect()
+    } else if download_mb.is_empty() {
+        preset.download_sizes.to_vec()
+    } else {
+        let sizes = download_mb
+            .iter()
+            .map(|size_mb| format!("{size_mb}MB"))
+            .collect::<Vec<_>>()
+            .join(",");
+        errln!("⚠️  --download-mb 已弃用，请改用 --download-size（如 -d {sizes}）");
+        download_mb
+            .into_iter()
+            .map(|size_mb| u64::from(size_mb) << 20)
+            .collect()
+    };
+    download_sizes.sort_unstable();
+    download_sizes.dedup();
+    // 最大的大小作为排名用的速度，其余的只是规模扫描
+    let download_size = download_sizes.pop();
+    let sweep_sizes = download_sizes;
+
+    let grade_thresholds = if grade || columns.contains(&Column::Grade) {
+        match GradeThresholds::new(grade_latency, grade_speed) {
+            Ok(thresholds) => Some(thresholds),
+            Err(e) => {
+                errln!("❌ {}", e);
+                return Ok(());
+            }
+        }
+    } else {
+        None
+    };
+    let color_grades = std::io::stdout().is_terminal();
+
+    let stdin_tags = if tags_stdin {
+        let patterns: Vec<String> = std::io::stdin()
+            .lines()
+            .map_while(Result::ok)
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                if tags_literal {
+                    format!("^{}$", regex::escape(&line))
+                } else {
+                    line
+                }
+            })
+            .collect();
+        outln!("📥 从标准输入读取到 {} 个 tag", patterns.len());
+        Some(RegexSet::new(patterns)?)
+    } else {
+        None
+    };
+    let whitelist_patterns = RegexSet::new(whitelist_patterns)?;
+    let group_by = group_by.as_deref().map(Regex::new).transpose()?;
+    if group_by
+        .as_ref()
+        .is_some_and(|regex| regex.captures_len() < 2)
+    {
+        errln!("❌ --group-by 的正则需要至少一个捕获组，例如 '^([A-Z]+)-'");
+        return Ok(());
+    }
+    let blacklist_patterns = RegexSet::new(blacklist_patterns)?;
+
+    let config: Config = if !socks.is_empty() {
+        // 每个 --socks 地址当作一个 socks inbound；不同主机的端口可能相同，
+        // 所以用地址在列表中的序号作为节点的内部端口
+        let mut addresses = HashMap::new();
+        let mut inbounds = Vec::new();
+        for (id, address) in (1..).zip(socks) {
+            if addresses.values().any(|previous| *previous == address) {
+                errln!("❌ --socks {} 重复给出", address);
+                return Ok(());
+            }
+            addresses.insert(id, address.clone());
+            inbounds.push(Inbound {
+                inbound_type: Some("socks".to_string()),
+                tag: Some(address.to_string()),
+                listen_port: Some(id),
+                listen: None,
+                users: Vec::new(),
+                extra: Default::default(),
+            });
+        }
+        let _ = AD_HOC_PROXIES.set(addresses);
+        Config {
+            inbounds: Some(inbounds),
+            experimental: None,
+        }
+    } else {
+        let config_content = if config_url.is_some() {
+            match fetch_config(&config_path, &config_header).await {
+                Ok(content) => content,
+                Err(e) => {
+                    errln!("❌ 无法下载配置: {}", e);
+                    return Ok(());
+                }
+            }
+        } else {
+            match fs::read_to_string(&config_path) {
+                Ok(content) => content,
+                Err(e) => {
+                    errln!("❌ 无法读取 JSON 文件: {}", e);
+                    return Ok(());
+                }
+            }
+        };
+
+        let config: Config = match serde_json::from_str(&config_content) {
+            Ok(config) => config,
+            Err(e) => {
+                errln!("❌ JSON 解析失败: {}", e);
+                return Ok(());
+            }
+        };
+
+        if strict_json {
+            // 上面已成功解析为 Config，这里再解析为任意 JSON 不会失败
+            let value: serde_json::Value = serde_json::from_str(&config_content)?;
+            let unknown = find_unknown_fields(&value);
+            if !unknown.is_empty() {
+                errln!("❌ 严格模式: 配置中存在 {} 个未知字段", unknown.len());
+                for field in unknown {
+                    errln!("   {}", field);
+                }
+                return Ok(());
+            }
+        }
+        config
+    };
+
+    let mut inbounds = match config.inbounds {
+        Some(inbounds) => inbounds,
+        None => {
+            errln!("❌ 未找到 inbounds 字段");
+            return Ok(());
+        }
+    };
+
+    // 声明了 users 的 inbound 需要认证，按 --user-index 选用其中一个
+    let mut proxy_auth = HashMap::new();
+    for inbound in &inbounds {
+        let (Some(port), Some(first)) = (inbound.listen_port, inbound.users.first()) else {
+            continue;
+        };
+        let user = inbound.users.get(user_index).unwrap_or_else(|| {
+            errln!(
+                "⚠️  {} 只有 {} 个用户，--user-index {} 超出范围，改用第一个",
+                inbound.tag.as_deref().unwrap_or("-"),
+                inbound.users.len(),
+                user_index
+            );
+            first
+        });
+        proxy_auth.insert(port, user.clone());
+    }
+    let _ = PROXY_AUTH.set(proxy_auth);
+
+    // --clash-api 时把 selector 的每个出站当作一个节点，全部经由同一个 socks inbound 测试
+    let clash = match (clash_api, clash_selector) {
+        (Some(url), Some(selector)) => {
+            let inbound = inbounds.iter().find(|inbound| {
+                inbound.inbound_type.as_deref() == Some("socks")
+                    && inbound.listen_port.is_some()
+                    && is_loopback_listen(inbound.listen.as_deref().unwrap_or("127.0.0.1"))
+                    && clash_inbound
+                        .as_ref()
+                        .is_none_or(|wanted| inbound.tag.as_ref() == Some(wanted))
+            });
+            let Some((inbound_tag, Some(port))) =
+                inbound.map(|inbound| (inbound.tag.clone(), inbound.listen_port))
+            else {
+                match &clash_inbound {
+                    Some(tag) => errln!("❌ 未找到监听在本机的 socks inbound: {tag}"),
+                    None => errln!("❌ --clash-api 需要配置中有一个监听在本机的 socks inbound"),
+                }
+                return Ok(());
+            };
+            let secret = config
+                .experimental
+                .and_then(|experimental| experimental.clash_api)
+                .and_then(|clash_api| clash_api.secret)
+                .filter(|secret| !secret.is_empty());
+            let api = match ClashApi::new(&url, secret, selector) {
+                Ok(api) => api,
+                Err(e) => {
+                    errln!("❌ --clash-api: {}", e);
+                    return Ok(());
+                }
+            };
+            let selector = match api.members().await {
+                Ok(selector) => selector,
+                Err(e) => {
+                    errln!("❌ 无法从 Clash API 读取 selector {}: {}", api.selector, e);
+                    return Ok(());
+                }
+            };
+            outln!(
+                "🎛️  通过 Clash API 切换 selector {} 的 {} 个出站，统一经由 {} (端口 {}) 测试",
+                api.selector,
+                selector.all.len(),
+                inbound_tag.as_deref().unwrap_or("-"),
+                port
+            );
+            inbounds = selector
+                .all
+                .into_iter()
+                .map(|tag| Inbound {
+                    inbound_type: Some("socks".to_string()),
+                    tag: Some(tag),
+                    listen_port: Some(port),
+                    listen: None,
+                    users: Vec::new(),
+                    extra: Default::default(),
+                })
+                .collect();
+            Some((api, selector.now))
+        }
+        _ => None,
+    };
+    let clash_api = clash.as_ref().map(|(api, _)| api);
+
+    let mut socks_nodes = Vec::new();
+    // --respect-node-target 时各节点自己的测试地址，按 tag 记录
+    let mut node_targets = HashMap::new();
+    // 标签匹配但类型不是 socks 而被跳过的 inbound，按类型计数
+    let mut skipped_types: BTreeMap<String, usize> = BTreeMap::new();
+    let mut unix_socket_tags = Vec::new();
+    let mut detected_schemes = HashMap::new();
+    // 透明代理类 inbound 没有可连接的本地代理端口，按类型记录 tag
+    let mut untestable: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut tag_excluded = 0; // 被 tag 过滤掉的 socks inbound
+
+    // 每条正则各自匹配到的 tag 数，以及同时满足全部正则的数量
+    let mut whitelist_hits = vec![0usize; whitelist_patterns.len()];
+    let mut blacklist_hits = vec![0usize; blacklist_patterns.len()];
+    let (mut whitelist_all, mut blacklist_all) = (0usize, 0usize);
+    for inbound in inbounds {
+        if let (Some(inbound_type), Some(tag), port, listen) = (
+            inbound.inbound_type,
+            inbound.tag,
+            inbound.listen_port,
+            inbound.listen,
+        ) {
+            let white = whitelist_patterns.matches(&tag);
+            let black = blacklist_patterns.matches(&tag);
+            white.iter().for_each(|idx| whitelist_hits[idx] += 1);
+            black.iter().for_each(|idx| blacklist_hits[idx] += 1);
+            whitelist_all += usize::from(white.matched_all());
+            blacklist_all += usize::from(black.matched_all());
+
+            let tag_matches = white.matched_all()
+                && (blacklist_patterns.is_empty() || !black.matched_all())
+                && stdin_tags.as_ref().is_none_or(|tags| tags.is_match(&tag));
+            if !tag_matches {
+                tag_excluded += usize::from(inbound_type == "socks");
+                continue;
+            }
+
+            let listen_addr = listen.unwrap_or_else(|| "127.0.0.1".to_string());
+            let is_local = is_loopback_listen(&listen_addr);
+            let added = socks_nodes.len();
+
+            if inbound_type == "socks" {
+                // listen 为路径（或 @ 开头的抽象地址）时是 unix socket，无法通过 TCP 端口测试
+                if listen_addr.starts_with('/') || listen_addr.starts_with('@') {
+                    unix_socket_tags.push(tag);
+                } else if let Some(port) = port {
+                    if is_local {
+                        socks_nodes.push((tag, port));
+                    }
+                }
+            } else if let Some(port) = port.filter(|_| autodetect_protocol && is_local) {
+                match detect_protocol(port, &probe_host).await {
+                    Some(scheme) => {
+                        outln!("🔎 {tag} ({inbound_type}, 端口 {port}) 探测为 {scheme} 代理");
+                        detected_schemes.insert(port, scheme);
+                        socks_nodes.push((tag, port));
+                    }
+                    None => *skipped_types.entry(inbound_type).or_default() += 1,
+                }
+            } else {
+                if matches!(inbound_type.as_str(), "tun" | "tproxy" | "redirect") {
+                    untestable
+                        .entry(inbound_type.clone())
+                        .or_default()
+                        .push(tag);
+                }
+                *skipped_types.entry(inbound_type).or_default() += 1;
+            }
+
+            // 只为确实要测试的节点读取测试地址
+            if let Some((tag, _)) = socks_nodes.get(added).filter(|_| respect_node_target) {
+                if let Some(url) = inbound_target(tag, &inbound.extra, danger_allow_http) {
+                    node_targets.insert(tag.clone(), url);
+                }
+            }
+        }
+    }
+    if respect_node_target {
+        outln!("🎯 {} 个节点使用自己的测试地址", node_targets.len());
+    }
+
+    for (name, patterns, hits, all) in [
+        (
+            "白名单",
+            &whitelist_patterns,
+            &whitelist_hits,
+            whitelist_all,
+        ),
+        (
+            "黑名单",
+            &blacklist_patterns,
+            &blacklist_hits,
+            blacklist_all,
+        ),
+    ] {
+        if patterns.is_empty() {
+            continue;
+        }
+        let mut line = patterns
+            .patterns()
+            .iter()
+            .zip(hits)
+            .map(|(pattern, n)| format!("'{pattern}' 匹配 {n} 个"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        if patterns.len() > 1 {
+            line += &format!(", 同时满足 {all} 个");
+        }
+        outln!("🔍 {name}: {line}");
+    }
+
+    let skipped_summary = (!skipped_types.is_empty()).then(|| {
+        let count: usize = skipped_types.values().sum();
+        let types = skipped_types
+            .iter()
+            .map(|(inbound_type, n)| format!("{inbound_type}×{n}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!("另有 {count} 个匹配的 inbound 因类型不是 socks 被跳过: {types}")
+    });
+
+    let _ = DETECTED_SCHEMES.set(detected_schemes);
+
+    if !untestable.is_empty() {
+        let listed = untestable
+            .iter()
+            .map(|(inbound_type, tags)| format!("{inbound_type}: {}", tags.join(", ")))
+            .collect::<Vec<_>>()
+            .join("; ");
+        outln!("ℹ️  以下 inbound 没有本地代理端口，无法直接测试延迟 — {listed}");
+    }
+
+    if !unix_socket_tags.is_empty() {
+        outln!(
+            "⚠️  暂不支持监听在 unix socket 上的 socks inbound，已跳过 {} 个: {}",
+            unix_socket_tags.len(),
+            unix_socket_tags.join(", ")
+        );
+    }
+
+    if socks_nodes.is_empty() {
+        if whitelist_patterns.is_empty() && stdin_tags.is_none() {
+            errln!("❌ 未找到任何 socks 类型的 inbound");
+            if !skipped_types.is_empty() {
+                let found = skipped_types.keys().cloned().collect::<Vec<_>>().join(", ");
+                errln!("   配置中找到的类型: {found}");
+                errln!("   目前只能测试 socks inbound，请在配置中为要测试的节点添加 socks 入口");
+            }
+        } else {
+            errln!("❌ 未找到匹配正则表达式的 socks 节点");
+            errln!("   白名单正则: {whitelist_patterns:?}");
+            errln!("   黑名单正则: {blacklist_patterns:?}");
+            if let Some(tags) = &stdin_tags {
+                errln!("   标准输入 tag: {} 个", tags.len());
+            }
+            if let Some(summary) = &skipped_summary {
+                errln!("   {summary}");
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(summary) = &skipped_summary {
+        outln!("⚠️  {summary}");
+    }
+
+    if only_changed.is_some() && baseline.is_none() {
+        errln!("❌ --only-changed 需要配合 --baseline 使用");
+        return Ok(());
+    }
+    if only_changed.is_some_and(|percent| !(percent >= 0.0 && percent.is_finite())) {
+        errln!("❌ --only-changed 必须是非负数");
+        return Ok(());
+    }
+    if retest_top.is_some() && baseline.is_none() {
+        errln!("❌ --retest-top 需要配合 --baseline 使用");
+        return Ok(());
+    }
+    if !(ewma_alpha > 0.0 && ewma_alpha <= 1.0) {
+        errln!("❌ --ewma-alpha 必须在 (0, 1] 之间");
+        return Ok(());
+    }
+    // 健康记录在测试前读取，文件损坏时不必白跑一轮
+    let mut health: Option<BTreeMap<String, NodeHealth>> = match &health_file {
+        Some(path) => match fs::read_to_string(path) {
+            Ok(content) => match serde_json::from_str(&content) {
+                Ok(health) => Some(health),
+                Err(e) => {
+                    errln!("❌ 无法解析健康记录 {}: {}", path, e);
+                    return Ok(());
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Some(BTreeMap::new()),
+            Err(e) => {
+                errln!("❌ 无法读取健康记录 {}: {}", path, e);
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+    // --retest-top 取基线中排名靠前的节点；文件顺序可能被 --export-sort 或
+    // --failures-first 打乱，所以重新排名
+    let mut retest_tags = None;
+    let baseline_path = baseline.clone();
+    let baseline: Option<HashMap<String, NodeResult>> = match &baseline {
+        Some(path) => {
+            let loaded: Result<Vec<NodeResult>, String> = fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|content| serde_json::from_str(&content).map_err(|e| e.to_string()));
+            match loaded {
+                Ok(mut previous) => {
+                    if let Some(n) = retest_top {
+                        let by_speed = previous.iter().any(|r| r.speed.is_some());
+                        previous.sort_by(|a, b| rank_order(a, b, by_speed));
+                        let top: Vec<String> =
+                            previous.iter().take(n).map(|r| r.tag.clone()).collect();
+                        retest_tags = Some(top);
+                    }
+                    Some(previous.into_iter().map(|r| (r.tag.clone(), r)).collect())
+                }
+                Err(e) => {
+                    errln!("❌ 无法读取基线文件 {}: {}", path, e);
+                    return Ok(());
+                }
+            }
+        }
+        None => None,
+    };
+
+    if let Some(path) = &alias_file {
+        let aliases: HashMap<String, String> = match fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| serde_json::from_str(&content).map_err(|e| e.to_string()))
+        {
+            Ok(aliases) => aliases,
+            Err(e) => {
+                errln!("❌ 无法读取别名文件 {}: {}", path, e);
+                return Ok(());
+            }
+        };
+        let mut renamed = 0;
+        for (tag, _) in &mut socks_nodes {
+            if let Some(alias) = aliases.get(tag.as_str()) {
+                if let Some(url) = node_targets.remove(tag.as_str()) {
+                    node_targets.insert(alias.clone(), url);
+                }
+                *tag = alias.clone();
+                renamed += 1;
+            }
+        }
+        outln!(
+            "🏷️  已按别名文件重命名 {}/{} 个节点",
+            renamed,
+            socks_nodes.len()
+        );
+    }
+
+    // 基线的 tag 是别名替换后的名字，所以放在别名之后匹配
+    if let Some(top) = &retest_tags {
+        let before = socks_nodes.len();
+        socks_nodes.retain(|(tag, _)| top.contains(tag));
+        let missing: Vec<&str> = top
+            .iter()
+            .filter(|tag| !socks_nodes.iter().any(|(current, _)| current == *tag))
+            .map(String::as_str)
+            .collect();
+        outln!(
+            "🔁 只重测基线中排名前 {} 的节点: {}/{} 个",
+            top.len(),
+            socks_nodes.len(),
+            before
+        );
+        if !missing.is_empty() {
+            outln!(
+                "⚠️  基线前 {} 名中有 {} 个不在当前配置中: {}",
+                top.len(),
+                missing.len(),
+                missing.join(", ")
+            );
+        }
+        if socks_nodes.is_empty() {
+            errln!("❌ 基线中排名靠前的节点都不在当前配置中");
+            return Ok(());
+        }
+    }
+
+    if let Some(required) = min_nodes.filter(|&required| socks_nodes.len() < required) {
+        errln!(
+            "❌ 只匹配到 {} 个节点，少于 --min-nodes 要求的 {} 个",
+            socks_nodes.len(),
+            required
+        );
+        // 用于监控时需要非零退出码，其余错误仍按原样正常退出
+        std::process::exit(1);
+    }
+
+    if latency_count == 0 {
+        errln!("❌ --latency-count 必须大于 0");
+        return Ok(());
+    }
+    if min_valid_ratio.is_some_and(|ratio| !(0.0..=1.0).contains(&ratio)) {
+        errln!("❌ --min-valid-ratio 必须在 0 到 1 之间");
+        return Ok(());
+    }
+    if node_budget.is_some_and(|secs| !(secs > 0.0 && secs.is_finite())) {
+        errln!("❌ --node-budget 必须是正数");
+        return Ok(());
+    }
+    match latency_url.split_once("://") {
+        Some(("https", _)) => {}
+        Some(("http", _)) if danger_allow_http => {
+            errln!("⚠️  使用明文 HTTP 测试地址，流量内容对代理及沿途可见");
+        }
+        Some(("http", _)) => {
+            errln!("❌ --latency-url 是明文 HTTP 地址，确认要使用请加上 --danger-allow-http");
+            return Ok(());
+        }
+        _ => {
+            errln!("❌ --latency-url 必须是 http:// 或 https:// 地址");
+            return Ok(());
+        }
+    }
+    if !(histogram_bucket > 0.0 && histogram_bucket.is_finite()) {
+        errln!("❌ --histogram-bucket 必须是正数");
+        return Ok(());
+    }
+    if reuse == Some(0) {
+        errln!("❌ --reuse 必须大于 0");
+        return Ok(());
+    }
+    if measure_error_latency && tcp_ping {
+        errln!("⚠️  --tcp-ping 不发送 HTTP 请求，--measure-error-latency 不起作用");
+    }
+    if reuse.is_some() && tcp_ping {
+        errln!("⚠️  --tcp-ping 每次探测都是新连接，--reuse 不起作用");
+    }
+    if min_download_time.is_some_and(|secs| !(secs > 0.0 && secs.is_finite())) {
+        errln!("❌ --min-download-time 必须是正数");
+        return Ok(());
+    }
+    if ramp.is_some_and(|secs| !(secs >= 0.0 && secs.is_finite())) {
+        errln!("❌ --ramp 必须是非负数");
+        return Ok(());
+    }
+    let ramp = ramp.map(Duration::from_secs_f64);
+    if let Some(budget) = retry_budget {
+        RETRY_BUDGET.store(budget, Ordering::Relaxed);
+    }
+    if speed_floor.is_some_and(|mbps| !(mbps > 0.0 && mbps.is_finite())) {
+        errln!("❌ --speed-floor 必须是正数");
+        return Ok(());
+    }
+    if overlap_probes && download_size.is_none() {
+        errln!("⚠️  --overlap-probes 需要配合 -d 开启速度测试才会生效");
+    }
+    if latency_concurrency == 0 || speed_concurrency == 0 {
+        errln!("❌ --latency-concurrency 和 --speed-concurrency 必须大于 0");
+        return Ok(());
+    }
+    let phased = latency_concurrency > 1 || speed_concurrency > 1;
+    if phased && clash_api.is_some() {
+        errln!("❌ --clash-api 同一时间只能选中一个出站，不能与并发测试同时使用");
+        return Ok(());
+    }
+    if phased && overlap_probes {
+        errln!("⚠️  并发测试时延迟与速度分阶段进行，--overlap-probes 不起作用");
+    }
+    if speed_samples == 0 {
+        errln!("❌ --speed-samples 必须大于 0");
+        return Ok(());
+    }
+    if speed_samples > 1 && download_size.is_none() {
+        errln!("⚠️  --speed-samples 需要配合 -d 开启速度测试才会生效");
+    }
+    if !(0.0..0.5).contains(&trim_fraction) {
+        errln!("❌ --trim-fraction 必须在 0 到 0.5 之间 (不含 0.5)");
+        return Ok(());
+    }
+    let min_valid = match (min_valid, min_valid_ratio) {
+        (Some(min_valid), _) => min_valid,
+        (None, Some(ratio)) => (ratio * latency_count as f64).ceil() as usize,
+        (None, None) => 3,
+    }
+    .clamp(1, latency_count);
+
+    if local_address.is_some() && !leak_check {
+        errln!("⚠️  --local-address 仅作用于直连请求，目前只有 --leak-check 会发起直连");
+    }
+    let direct_ip = if leak_check && !print_config {
+        match fetch_trace(TraceRoute::Direct(local_address)).await {
+            Ok(trace) if trace.contains_key("ip") => {
+                outln!("🌐 直连 IP: {}", trace["ip"]);
+                trace.get("ip").cloned()
+            }
+            Ok(_) => {
+                errln!("⚠️  直连 trace 响应中没有 ip 字段，跳过泄漏检查");
+                None
+            }
+            Err(e) => {
+                errln!("⚠️  无法获取直连 IP ({})，跳过泄漏检查", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let options = TestOptions {
+        latency_count,
+        min_valid,
+        download_size,
+        sweep_sizes,
+        segments,
+        accept_status,
+        measure_error_latency,
+        timeout_as_slow,
+        speed_warmup,
+        skip_latency_warmup: no_warmup_latency,
+        report_warmup,
+        latency_under_load,
+        tcp_ping: tcp_ping.then_some(probe_host),
+        direct_ip,
+        trim_fraction: robust.then_some(trim_fraction),
+        confidence_interval: ci,
+        node_budget: node_budget.map(Duration::from_secs_f64),
+        deadline: None,
+        detect_country: !country_filter.is_empty(),
+        histogram_bucket: histogram.then_some(histogram_bucket),
+        latency_url: latency_url.clone(),
+        node_targets,
+        honor_retry_after,
+        retry_transient,
+        think_time: think_time.map(Duration::from_millis),
+        jitter,
+        dns_probe,
+        check_ipv6,
+        defer_speed: false,
+        min_download_time: min_download_time.map(Duration::from_secs_f64),
+        speed_samples,
+        speed_floor,
+        abort_below_floor,
+        overlap_probes,
+        show_tls,
+        reuse,
+        load_levels: load_test.into_iter().filter(|&level| level > 0).collect(),
+    };
+
+    if print_config {
+        let nodes: Vec<_> = socks_nodes
+            .iter()
+            .map(|(tag, port)| serde_json::json!({ "tag": tag, "port": port }))
+            .collect();
+        let effective = serde_json::json!({
+            "config": config_path,
+            "nodes": nodes,
+            "tests": options,
+            "profile": profile,
+            "output": {
+                "format": format,
+                "columns": columns,
+                "output_dir": output_dir,
+                "failures_output": failures_output,
+                "table_style": table_style,
+                "speed_unit": speed_unit,
+                "precision": precision,
+                "select": select,
+                "no_sort": no_sort,
+                "failures_first": failures_first,
+                "export_sort": export_sort,
+                "top": top,
+                "summary_only": summary_only,
+                "stream_results": stream_results,
+                "only_changed": only_changed,
+                "retest_top": retest_top,
+                "min_nodes": min_nodes,
+                "health_file": health_file,
+                "ewma_alpha": ewma_alpha,
+                "plain_symbols": plain_symbols,
+                "locale": NUMBER_LOCALE.get(),
+                "grade_thresholds": grade_thresholds,
+                "group_by": group_by.as_ref().map(Regex::as_str),
+                "alias_file": alias_file,
+                "baseline": baseline_path,
+            },
+            "run": {
+                "resume": resume,
+                "retry_unstable": retry_unstable,
+                "retry_budget": retry_budget,
+                "on_result": on_result,
+                "country": country_filter,
+                "latency_concurrency": latency_concurrency,
+                "speed_concurrency": speed_concurrency,
+                "ramp": ramp.map(|ramp| ramp.as_secs_f64()),
+                "dns_cache": dns_cache,
+                "user_agent": USER_AGENT.get(),
+                "danger_accept_invalid_certs": ACCEPT_INVALID_CERTS.load(Ordering::Relaxed),
+                "user_index": user_index,
+                "respect_node_target": respect_node_target,
+                "autodetect_protocol": autodetect_protocol,
+            },
+            "clash_api": clash_api.map(|api| serde_json::json!({
+                "url": api.url.as_str(),
+                "selector": api.selector,
+            })),
+        });
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&effective).expect("options are always serializable")
+        );
+        return Ok(());
+    }
+
+    let mut checkpoint = match &resume {
+        Some(path) => {
+            let params = RunParams {
+                config_path: config_path.clone(),
+                nodes: socks_nodes.clone(),
+                download_size,
+                accept_status: options.accept_status.to_string(),
+                latency_count,
+                min_valid,
+                speed_warmup,
+                no_warmup_latency,
+                latency_under_load,
+                tcp_ping: options.tcp_ping.as_ref().map(ToString::to_string),
+                trim_fraction: options.trim_fraction,
+                confidence_interval: options.confidence_interval,
+                node_budget,
+                min_download_time,
+                reuse,
+                detect_country: options.detect_country,
+                latency_url: latency_url.clone(),
+                respect_node_target,
+                think_time,
+                dns_probe,
+                check_ipv6,
+                speed_samples,
+                measure_error_latency,
+                timeout_as_slow,
+                speed_floor,
+                abort_below_floor,
+                overlap_probes,
+                sweep_sizes: options.sweep_sizes.clone(),
+                segments,
+                user_agent: USER_AGENT.get().cloned(),
+                danger_accept_invalid_certs: ACCEPT_INVALID_CERTS.load(Ordering::Relaxed),
+                user_index,
+                leak_check,
+                show_tls,
+                honor_retry_after,
+                retry_transient,
+                jitter,
+            };
+            match Checkpoint::load(path, params) {
+                Ok(checkpoint) => {
+                    if !checkpoint.results.is_empty() {
+                        outln!(
+                            "💾 从断点恢复: 已完成 {}/{} 个节点\n",
+                            checkpoint.results.len(),
+                            socks_nodes.len()
+                        );
+                    }
+                    Some((path.as_str(), checkpoint))
+                }
+                Err(e) => {
+                    errln!("❌ {}", e);
+                    return Ok(());
+                }
+            }
+        }
+        None => None,
+    };
+
+    if let Some(limit) = confirm_over.filter(|&limit| socks_nodes.len() > limit && !yes) {
+        let prompt = format!(
+            "⚠️  匹配到 {} 个节点，超过 --confirm-over {limit}",
+            socks_nodes.len()
+        );
+        if !std::io::stdin().is_terminal() {
+            errln!("{prompt}，非交互模式下需要加 --yes 才会继续");
+            return Ok(());
+        }
+        eprint!("{prompt}，确定继续吗？[y/N] ");
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            errln!("已取消");
+            return Ok(());
+        }
+    }
+
+    let mode = if phased { "分阶段并发" } else { "顺序" };
+    let test_description = if let Some(size) = download_size {
+        let sizes = options
+            .sweep_sizes
+            .iter()
+            .chain([&size])
+            .map(|&bytes| format_bytes(bytes))
+            .collect::<Vec<_>>()
+            .join(" / ");
+        format!(
+            "找到 {} 个 socks 节点，开始{}测试（延迟测试{}次 + 下载测试 {}）\n",
+            socks_nodes.len(),
+            mode,
+            latency_count,
+            sizes
+        )
+    } else {
+        format!(
+            "找到 {} 个 socks 节点，开始{}测试（每节点{}次延迟测试）\n",
+            socks_nodes.len(),
+            mode,
+            latency_count
         )
     };
 
-    println!("🚀 {}", test_description);
-    println!("{}", "=".repeat(80));
+    // ndjson 不等排序，每个节点完成时立即输出一行
+    let mut ndjson_sink: Option<Box<dyn std::io::Write>> =
+        match (format.contains(&OutputFormat::Ndjson), &output_dir) {
+            (false, _) => None,
+            (true, None) => Some(Box::new(std::io::stdout())),
+            (true, Some(dir)) => {
+                let path = std::path::Path::new(dir).join("results.ndjson");
+                match fs::create_dir_all(dir).and_then(|_| fs::File::create(&path)) {
+                    Ok(file) => {
+                        outln!("💾 逐行写入 {}", path.display());
+                        Some(Box::new(file))
+                    }
+                    Err(e) => {
+                        errln!("❌ 无法写入 {}: {}", path.display(), e);
+                        None
+                    }
+                }
+            }
+        };
+
+    outln!("🚀 {}", test_description);
+    outln!("{}", "=".repeat(80));
 
     let mut results = Vec::new();
+    let run_start = Instant::now();
+    let total = socks_nodes.len();
+    let mut tested = 0;
+    let mut pending = Vec::new();
 
     for (idx, (tag, port)) in socks_nodes.iter().enumerate() {
         let current = idx + 1;
-        let total = socks_nodes.len();
 
-        println!(
+        let finished = checkpoint.as_ref().and_then(|(_, checkpoint)| {
+            checkpoint
+                .results
+                .iter()
+                .find(|r| &r.tag == tag && r.port == *port)
+        });
+        if let Some(finished) = finished {
+            outln!(
+                "⏭️  [{}/{}] 跳过已完成节点: {} (端口: {})",
+                current,
+                total,
+                tag,
+                port
+            );
+            write_ndjson(&mut ndjson_sink, finished);
+            results.push(finished.clone());
+            continue;
+        }
+
+        // 分阶段测试时先占位，两个阶段结束后再填入结果
+        if phased {
+            pending.push((results.len(), current, tag.clone(), *port));
+            results.push(NodeResult::failed(tag, *port, LatencyResult::AllFailed));
+            continue;
+        }
+
+        outln!(
             "📡 [{}/{}] 测试节点: {} (端口: {})",
-            current, total, tag, port
+            current,
+            total,
+            tag,
+            port
         );
 
-        print!("  延迟测试: ");
-        let latency = test_node_latency(*port, 10).await;
+        let result = test_node_via(clash_api, tag, *port, &options).await;
+        if stream_results {
+            // 单次 println! 持有 stdout 锁，整行输出不会与其它输出交错
+            outln!("📋 [结果 {}/{}] {}", current, total, result.summary_line());
+        }
+        if let Some((path, checkpoint)) = &mut checkpoint {
+            checkpoint.record(path, &result);
+        }
+        write_ndjson(&mut ndjson_sink, &result);
+        if let Some(command) = &on_result {
+            run_result_hook(command, &result).await;
+        }
+        results.push(result);
+        tested += 1;
+
+        // 基于吞吐量（节点/秒）估算剩余时间
+        let elapsed = run_start.elapsed();
+        let remaining = total - current;
+        if remaining > 0 {
+            let throughput = tested as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+            let eta = Duration::from_secs_f64(remaining as f64 / throughput);
+            outln!(
+                "  ⏱️  已用时 {}，预计剩余 {}",
+                format_duration(elapsed),
+                format_duration(eta)
+            );
+        }
+        outln!();
+    }
 
-        match &latency {
-            LatencyResult::Success {
-                median,
-                average,
-                minimum,
-                maximum,
-            } => {
-                println!("✅ {median:.2}/{average:.2}/{minimum:.2}/{maximum:.2} ms");
+    if !pending.is_empty() {
+        // 节点的结果确定后才写入断点、输出并调用 --on-result，
+        // 否则中断后 --resume 会把只测了延迟的节点当作已完成
+        let speed_phase = download_size.is_some();
+        let mut publish = async |current: usize, result: &NodeResult| {
+            if stream_results {
+                outln!("📋 [结果 {}/{}] {}", current, total, result.summary_line());
             }
-            LatencyResult::Unstable(valid, total) => {
-                println!("⚠️  不稳定 ({}/{} 次成功)", valid, total);
+            if let Some((path, checkpoint)) = &mut checkpoint {
+                checkpoint.record(path, result);
             }
-            LatencyResult::AllFailed => {
-                println!("❌ 全部失败");
-            }
-            LatencyResult::SessionError(err) => {
-                println!("❌ 连接错误: {}", err);
+            write_ndjson(&mut ndjson_sink, result);
+            if let Some(command) = &on_result {
+                run_result_hook(command, result).await;
             }
+        };
+
+        let phase_options = std::sync::Arc::new(TestOptions {
+            defer_speed: true,
+            ..options.clone()
+        });
+        outln!(
+            "⏱️  第一阶段: 延迟测试，同时测试 {} 个节点\n",
+            latency_concurrency
+        );
+        let partial = run_concurrently(
+            pending.clone(),
+            latency_concurrency,
+            ramp,
+            |(_, current, tag, port)| {
+                let options = phase_options.clone();
+                async move {
+                    outln!(
+                        "📡 [{}/{}] 测试节点: {} (端口: {})",
+                        current,
+                        total,
+                        tag,
+                        port
+                    );
+                    test_node(&tag, port, &options).await
+                }
+            },
+            async |idx, result: &NodeResult| {
+                let current = pending[idx].1;
+                if !speed_phase {
+                    publish(current, result).await;
+                } else if stream_results {
+                    // 还要测速，这里只在控制台显示延迟部分
+                    outln!("⏱️  [延迟 {}/{}] {}", current, total, result.summary_line());
+                }
+            },
+        )
+        .await;
+        for (&(slot, ..), result) in pending.iter().zip(&partial) {
+            results[slot] = result.clone();
+        }
+
+        // 与顺序测试一致，延迟测试失败的节点同样测速
+        if speed_phase {
+            let speed_options = std::sync::Arc::new(options.clone());
+            outln!(
+                "\n🚄 第二阶段: 速度测试，同时测试 {} 个节点\n",
+                speed_concurrency
+            );
+            run_concurrently(
+                pending.clone(),
+                speed_concurrency,
+                ramp,
+                |(_, current, tag, port)| {
+                    let options = speed_options.clone();
+                    async move {
+                        outln!(
+                            "📡 [{}/{}] 速度测试: {} (端口: {})",
+                            current,
+                            total,
+                            tag,
+                            port
+                        );
+                        let own_target = options.for_node(&tag);
+                        let options = own_target.as_ref().unwrap_or(&options);
+                        let budgeted = options.budgeted();
+                        probe_node_speed(port, budgeted.as_ref().unwrap_or(options)).await
+                    }
+                },
+                async |idx, (speed, speed_samples, sweep, peak_speed): &_| {
+                    let (slot, current, _, _) = pending[idx];
+                    let result = &mut results[slot];
+                    result.speed = speed.clone();
+                    result.speed_samples = speed_samples.clone();
+                    result.sweep = sweep.clone();
+                    result.peak_speed = *peak_speed;
+                    publish(current, result).await;
+                },
+            )
+            .await;
         }
+        outln!();
+    }
+
+    // 对不稳定 / 连接错误的节点进行一次重试
+    let mut rescued = None;
+    if retry_unstable {
+        let retry_indices: Vec<usize> = results
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| {
+                matches!(
+                    r.latency,
+                    LatencyResult::Unstable(..) | LatencyResult::SessionError(_)
+                )
+            })
+            .map(|(idx, _)| idx)
+            .collect();
 
-        let speed = if let Some(size_mb) = download_mb {
-            println!("  速度测试:");
-            let speed_result = test_node_speed(*port, size_mb).await;
+        if !retry_indices.is_empty() {
+            outln!("🔁 重试 {} 个不稳定/连接错误的节点\n", retry_indices.len());
+        }
 
-            match &speed_result {
-                SpeedResult::Success(mbps) => {
-                    println!("  ✅ 下载速度: {:.2} Mbps", mbps);
+        let mut rescued_count = 0;
+        for (n, &idx) in retry_indices.iter().enumerate() {
+            if !take_retry() {
+                outln!(
+                    "⏹️  重试预算已用完，其余 {} 个节点不再重试\n",
+                    retry_indices.len() - n
+                );
+                break;
+            }
+            let (tag, port) = (results[idx].tag.clone(), results[idx].port);
+            outln!(
+                "📡 [重试 {}/{}] 测试节点: {} (端口: {})",
+                n + 1,
+                retry_indices.len(),
+                tag,
+                port
+            );
+            let retried = test_node_via(clash_api, &tag, port, &options).await;
+            if matches!(retried.latency, LatencyResult::Success { .. }) {
+                outln!("  🎉 重试成功，采用新结果");
+                if let Some((path, checkpoint)) = &mut checkpoint {
+                    checkpoint.record(path, &retried);
                 }
-                SpeedResult::Failed(err) => {
-                    println!("  ❌ 速度测试失败: {}", err);
+                write_ndjson(&mut ndjson_sink, &retried);
+                if let Some(command) = &on_result {
+                    run_result_hook(command, &retried).await;
                 }
+                results[idx] = retried;
+                rescued_count += 1;
             }
-            Some(speed_result)
-        } else {
-            None
-        };
+            outln!();
+        }
+        rescued = Some((rescued_count, retry_indices.len()));
+    }
 
-        results.push(NodeResult {
-            tag: tag.clone(),
-            port: *port,
-            latency: latency.clone(),
-            speed,
+    if let Some((api, original)) = &clash {
+        if let Err(e) = api.select(original).await {
+            errln!(
+                "⚠️  无法把 selector {} 恢复为 {}: {}",
+                api.selector,
+                original,
+                e
+            );
+        }
+    }
+
+    let total_elapsed = run_start.elapsed();
+
+    // 所有节点都连不上时，多半是 SingBox 没有运行或端口不对，而不是节点本身有问题
+    let all_unreachable = !results.is_empty()
+        && results.iter().all(|r| {
+            matches!(
+                r.latency,
+                LatencyResult::AllFailed | LatencyResult::SessionError(_)
+            )
         });
-        println!();
-    }
-
-    // 排序
-    if download_mb.is_some() {
-        results.sort_by(|a, b| match (&a.speed, &b.speed) {
-            (Some(SpeedResult::Success(sa)), Some(SpeedResult::Success(sb))) => {
-                sb.partial_cmp(sa).unwrap_or(std::cmp::Ordering::Equal)
-            }
-            (Some(SpeedResult::Success(_)), _) => std::cmp::Ordering::Less,
-            (_, Some(SpeedResult::Success(_))) => std::cmp::Ordering::Greater,
-            _ => match (&a.latency, &b.latency) {
-                (
-                    LatencyResult::Success { median: ma, .. },
-                    LatencyResult::Success { median: mb, .. },
-                ) => ma.partial_cmp(mb).unwrap_or(std::cmp::Ordering::Equal),
-                (LatencyResult::Success { .. }, _) => std::cmp::Ordering::Less,
-                (_, LatencyResult::Success { .. }) => std::cmp::Ordering::Greater,
-                _ => std::cmp::Ordering::Equal,
-            },
+
+    if !country_filter.is_empty() {
+        let before = results.len();
+        let undetected = results.iter().filter(|r| r.country.is_none()).count();
+        results.retain(|r| {
+            r.country.as_ref().is_some_and(|country| {
+                country_filter
+                    .iter()
+                    .any(|wanted| wanted.eq_ignore_ascii_case(country))
+            })
         });
+        outln!(
+            "🌍 按出口国家过滤: 排除 {} 个（国家不符 {}，未检测到 {}），此前按 tag 排除 {} 个\n",
+            before - results.len(),
+            before - results.len() - undetected,
+            undetected,
+            tag_excluded
+        );
+    }
+
+    // 排序（--no-sort 时保持配置中的顺序）
+    // --no-sort 时结果本就按配置顺序测试，重试也是原地替换
+    if !no_sort {
+        match select {
+            Selection::Rank => {
+                let by_speed = download_size.is_some();
+                results.sort_by(|a, b| rank_order(a, b, by_speed));
+            }
+            Selection::StableFast => {
+                let lossy = |r: &NodeResult| r.loss_ratio(latency_count) > STABLE_FAST_MAX_LOSS;
+                results.sort_by(|a, b| {
+                    lossy(a)
+                        .cmp(&lossy(b))
+                        .then_with(|| rank_order(a, b, false))
+                });
+            }
+        }
+        if failures_first {
+            // 稳定排序，同类问题内部保持上面的排名
+            results.sort_by_key(|r| std::cmp::Reverse(r.severity()));
+        }
+    }
+
+    // --top 只影响展示和导出，总结仍统计全部节点
+    let shown = &results[..top.unwrap_or(results.len()).min(results.len())];
+
+    // 本次测到的节点计入健康记录，未测到的保持原样
+    if let (Some(health), Some(path)) = (&mut health, &health_file) {
+        for result in &results {
+            health
+                .entry(result.tag.clone())
+                .or_default()
+                .update(result, ewma_alpha);
+        }
+        let saved = serde_json::to_string_pretty(&*health)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(path, json).map_err(|e| e.to_string()));
+        if let Err(e) = saved {
+            errln!("❌ 无法写入健康记录 {}: {}", path, e);
+        }
+    }
+
+    // 输出结果表格（--columns 未指定时按开启的测试项决定列）
+    let show_exit_ip = options.direct_ip.is_some();
+    let table_columns = if !columns.is_empty() {
+        columns.clone()
     } else {
-        results.sort_by(|a, b| match (&a.latency, &b.latency) {
-            (
-                LatencyResult::Success { median: ma, .. },
-                LatencyResult::Success { median: mb, .. },
-            ) => ma.partial_cmp(mb).unwrap_or(std::cmp::Ordering::Equal),
-            (LatencyResult::Success { .. }, _) => std::cmp::Ordering::Less,
-            (_, LatencyResult::Success { .. }) => std::cmp::Ordering::Greater,
-            _ => std::cmp::Ordering::Equal,
+        let mut columns = Vec::new();
+        if grade_thresholds.is_some() {
+            columns.push(Column::Grade);
+        }
+        columns.extend([
+            Column::Rank,
+            Column::Status,
+            Column::Port,
+            Column::Median,
+            Column::Average,
+            Column::Minimum,
+            Column::Maximum,
+        ]);
+        for (column, enabled) in [
+            (Column::Trim, robust),
+            (Column::Ci, ci),
+            (Column::Speed, download_size.is_some()),
+            (Column::Peak, download_size.is_some()),
+            (Column::ExitIp, show_exit_ip),
+            (Column::Country, options.detect_country),
+            (Column::Dns, options.dns_probe),
+            (Column::Ipv6, options.check_ipv6),
+            (Column::Http, options.measure_error_latency),
+            (Column::Tls, options.show_tls),
+            (Column::Health, health.is_some()),
+            (Column::Baseline, baseline.is_some()),
+        ] {
+            if enabled {
+                columns.push(column);
+            }
+        }
+        columns.push(Column::Tag);
+        columns
+    };
+    let mut table = TextTable::new();
+    for column in &table_columns {
+        table.header(match column {
+            Column::Grade => "评级".to_string(),
+            Column::Rank if no_sort => "序号".to_string(),
+            Column::Rank => "排名".to_string(),
+            Column::Status => "STATUS".to_string(),
+            Column::Port => "端口".to_string(),
+            Column::Median => "med".to_string(),
+            Column::Average => "avg".to_string(),
+            Column::Minimum => "min".to_string(),
+            Column::Maximum => "max".to_string(),
+            Column::Trim => "trim".to_string(),
+            Column::Ci => "±95%".to_string(),
+            Column::Speed => format!("速度{}", SpeedUnit::current().label()),
+            Column::Peak => format!("峰值{}", SpeedUnit::current().label()),
+            Column::ExitIp => "出口IP".to_string(),
+            Column::Country => "国家".to_string(),
+            Column::Dns => "DNS".to_string(),
+            Column::Ipv6 => "IPv6".to_string(),
+            Column::Http => "HTTP".to_string(),
+            Column::Tls => "TLS".to_string(),
+            Column::Health => "健康度".to_string(),
+            Column::Baseline => "基线变化".to_string(),
+            Column::Tag => "节点名称 (tag)".to_string(),
         });
     }
 
-    // 输出结果表格
-    println!(
-        "{}",
-        "=".repeat(if download_mb.is_some() { 125 } else { 110 })
-    );
-
-    if download_mb.is_some() {
-        println!(
-            "{:<4} {:<8} {:<8} {:<8} {:<8} {:<8} {:<12} {:<45}",
-            "排名", "端口", "med", "avg", "min", "max", "速度Mbps", "节点名称 (tag)"
+    // 相对基线变化超过阈值、状态改变或新出现的节点
+    let moved = |result: &NodeResult| {
+        let (Some(baseline), Some(threshold)) = (&baseline, only_changed) else {
+            return true;
+        };
+        let Some(old) = baseline.get(&result.tag) else {
+            return true;
+        };
+        let (latency, speed) = result.change_from(old);
+        old.status_label() != result.status_label()
+            || [latency, speed]
+                .into_iter()
+                .flatten()
+                .any(|change| change.abs() > threshold)
+    };
+    if let Some(threshold) = only_changed {
+        outln!(
+            "\n🔎 仅显示相对基线变化超过 {}% 的节点 ({}/{})",
+            threshold,
+            shown.iter().filter(|r| moved(r)).count(),
+            shown.len()
         );
-        println!("{}", "-".repeat(125));
+    }
 
-        for (rank, result) in results.iter().enumerate() {
-            let rank = rank + 1;
-            match (&result.latency, result.speed.as_ref()) {
-                (
-                    LatencyResult::Success {
-                        median,
-                        average,
-                        minimum,
-                        maximum,
-                    },
-                    Some(SpeedResult::Success(speed)),
-                ) => {
-                    println!("{:<4} {:<10} {median:<8.2} {average:<8.2} {minimum:<8.2} {maximum:<8.2} {speed:<12.2} {:<45}", 
-                             rank, result.port, result.tag);
-                }
-                (
+    let ms = |value: f64| Cell::from(decimal(value));
+    let compact = format.contains(&OutputFormat::TableCompact);
+    let mut compact_lines = Vec::new();
+    for (rank, result) in shown.iter().enumerate() {
+        if !moved(result) {
+            continue;
+        }
+        // --no-sort 时显示节点在配置中的序号（过滤后可能不连续）
+        let index = if no_sort {
+            socks_nodes
+                .iter()
+                .position(|(tag, port)| *tag == result.tag && *port == result.port)
+                .unwrap_or(rank)
+        } else {
+            rank
+        };
+        let color = match &grade_thresholds {
+            Some(thresholds) => thresholds.grade(result).color(),
+            None => match result.status_label() {
+                "OK" => "32",
+                "SLOW" | "UNSTABLE" => "33",
+                _ => "31",
+            },
+        };
+        compact_lines.push((result.compact_line(index + 1), color));
+        // 失败时状态写在 med 列，其余统计列留空
+        let stats = match &result.latency {
+            LatencyResult::Success {
+                median,
+                average,
+                minimum,
+                maximum,
+                ..
+            } => Some([median, average, minimum, maximum]),
+            _ => None,
+        };
+        let stat = |idx: usize| stats.map_or_else(Cell::default, |stats| ms(*stats[idx]));
+        let row = table_columns
+            .iter()
+            .map(|column| match column {
+                Column::Grade => {
+                    let Some(thresholds) = &grade_thresholds else {
+                        return Cell::default();
+                    };
+                    let grade = thresholds.grade(result);
+                    let cell = Cell::from(grade.letter().to_string());
+                    if color_grades {
+                        cell.colored(grade.color())
+                    } else {
+                        cell
+                    }
+                }
+                Column::Rank => Cell::from((index + 1).to_string()),
+                Column::Status => Cell::from(result.status_label()),
+                Column::Port => Cell::from(result.port.to_string()),
+                Column::Median if stats.is_none() => Cell::from(result.latency.to_string()),
+                Column::Median => stat(0),
+                Column::Average => stat(1),
+                Column::Minimum => stat(2),
+                Column::Maximum => stat(3),
+                Column::Trim => match result.latency {
                     LatencyResult::Success {
-                        median,
-                        average,
-                        minimum,
-                        maximum,
-                    },
-                    Some(SpeedResult::Failed(err)),
-                ) => {
-                    let err_display = if err.len() > 10 { &err[..10] } else { err };
-                    println!("{:<4} {:<10} {median:<8.2} {average:<8.2} {minimum:<8.2} {maximum:<8.2} {err_display:<12} {:<45}", 
-                             rank, result.port, result.tag);
-                }
-                _ => {
-                    let speed_str = result
-                        .speed
+                        trimmed_mean: Some(trimmed),
+                        ..
+                    } => ms(trimmed),
+                    _ => Cell::default(),
+                },
+                Column::Ci => match result.latency {
+                    LatencyResult::Success { ci: Some(ci), .. } => {
+                        Cell::from(format!("±{}", decimal(ci)))
+                    }
+                    _ => Cell::default(),
+                },
+                Column::Speed => match &result.speed {
+                    Some(SpeedResult::Success(speed)) => ms(SpeedUnit::current().convert(*speed)),
+                    Some(SpeedResult::TooSlow(_)) => Cell::from("Too Slow"),
+                    Some(SpeedResult::Failed { reason, .. }) => Cell::from(reason.to_string()),
+                    None => Cell::default(),
+                },
+                Column::Peak => result.peak_speed.map_or_else(
+                    || Cell::from("-"),
+                    |peak| ms(SpeedUnit::current().convert(peak)),
+                ),
+                Column::ExitIp => {
+                    let ip = result.exit_ip.clone().unwrap_or_else(|| "-".to_string());
+                    if result.leak_suspected {
+                        Cell::from(format!("{ip} LEAK?"))
+                    } else {
+                        Cell::from(ip)
+                    }
+                }
+                Column::Country => Cell::from(result.country.as_deref().unwrap_or("-")),
+                Column::Dns => result.dns_ms.map_or_else(|| Cell::from("-"), ms),
+                Column::Ipv6 => Cell::from(match result.ipv6_ok {
+                    Some(true) => "ok",
+                    Some(false) => "no",
+                    None => "-",
+                }),
+                Column::Http => Cell::from(
+                    result
+                        .http_error
+                        .map_or_else(|| "-".to_string(), |code| code.to_string()),
+                ),
+                Column::Tls => Cell::from(match &result.tls {
+                    Some(TlsCheck::Negotiated { version, .. }) => version.as_str(),
+                    Some(TlsCheck::Failed(_)) => "Failed",
+                    None => "-",
+                }),
+                Column::Health => Cell::from(
+                    health
                         .as_ref()
-                        .map(|s| format!("{}", s))
-                        .unwrap_or_default();
-                    println!(
-                        "{:<4} {:<10} {:<35} {:<12} {:<45}",
-                        rank, result.port, result.latency, speed_str, result.tag
-                    );
+                        .and_then(|health| health.get(&result.tag))
+                        .map_or_else(|| "-".to_string(), ToString::to_string),
+                ),
+                Column::Baseline => {
+                    let Some(baseline) = &baseline else {
+                        return Cell::from("-");
+                    };
+                    Cell::from(match baseline.get(&result.tag) {
+                        None => "new".to_string(),
+                        Some(old) if old.status_label() != result.status_label() => {
+                            format!("{} → {}", old.status_label(), result.status_label())
+                        }
+                        Some(old) => {
+                            let (latency, speed) = result.change_from(old);
+                            let mut parts = Vec::new();
+                            if let Some(latency) = latency {
+                                parts.push(format!("med {latency:+.0}%"));
+                            }
+                            if let Some(speed) = speed {
+                                parts.push(format!("速度 {speed:+.0}%"));
+                            }
+                            if parts.is_empty() {
+                                "-".to_string()
+                            } else {
+                                parts.join(", ")
+                            }
+                        }
+                    })
                 }
+                Column::Tag => Cell::from(result.tag.clone()),
+            })
+            .collect();
+        table.row(row);
+    }
+
+    let table_text = table.render(table_style);
+    if compact {
+        for (line, color) in &compact_lines {
+            if color_grades {
+                outln!("\x1b[{}m{}\x1b[0m", color, line);
+            } else {
+                outln!("{}", line);
             }
         }
     } else {
-        println!(
-            "{:} {:<8} {:<8} {:<8} {:<8} {:<8} {:<45}",
-            "排名", "端口", "med", "avg", "min", "max", "节点名称 (tag)"
-        );
-        println!("{}", "-".repeat(110));
-
-        for (rank, result) in results.iter().enumerate() {
-            let rank = rank + 1;
-            match &result.latency {
-                LatencyResult::Success {
-                    median,
-                    average,
-                    minimum,
-                    maximum,
-                } => {
-                    println!("{:<4} {:<10} {median:<8.2} {average:<8.2} {minimum:<8.2} {maximum:<8.2} {:<45}", 
-                             rank, result.port, result.tag);
-                }
-                _ => {
-                    println!(
-                        "{:<4} {:<10} {:<35} {:<45}",
-                        rank, result.port, result.latency, result.tag
-                    );
-                }
-            }
+        out!("{}", table_text);
+    }
+
+    // --retest-top 本来就只测一部分，不再列出其余节点
+    if let Some(baseline) = baseline.as_ref().filter(|_| retest_tags.is_none()) {
+        let mut removed: Vec<&str> = baseline
+            .keys()
+            .filter(|tag| !results.iter().any(|r| &r.tag == *tag))
+            .map(String::as_str)
+            .collect();
+        if !removed.is_empty() {
+            removed.sort_unstable();
+            outln!("➖ 基线中有但本次未测试: {}", removed.join(", "));
         }
     }
 
-    println!(
-        "{}",
-        "=".repeat(if download_mb.is_some() { 125 } else { 110 })
-    );
+    if latency_under_load {
+        outln!("\n🐢 负载下延迟 (med, ms):");
+        let mut table = TextTable::new();
+        for header in ["空闲", "负载", "增加", "节点名称 (tag)"] {
+            table.header(header);
+        }
+        for result in shown {
+            let mut row = match result.bufferbloat() {
+                Some((idle, busy)) => vec![
+                    Cell::from(decimal(idle)),
+                    Cell::from(decimal(busy)),
+                    Cell::from(signed_decimal(busy - idle)),
+                ],
+                None => vec![Cell::from("-"), Cell::from("-"), Cell::from("-")],
+            };
+            row.push(Cell::from(result.tag.clone()));
+            table.row(row);
+        }
+        out!("{}", table.render(table_style));
+    }
+
+    if let Some(group_by) = &group_by {
+        // 未匹配的节点归入 other，并放在最后
+        let mut groups: BTreeMap<String, Vec<&NodeResult>> = BTreeMap::new();
+        let mut other = Vec::new();
+        for result in &results {
+            match group_by.captures(&result.tag).and_then(|caps| caps.get(1)) {
+                Some(key) => groups
+                    .entry(key.as_str().to_string())
+                    .or_default()
+                    .push(result),
+                None => other.push(result),
+            }
+        }
+
+        outln!("\n🗂️  分组汇总:");
+        let mut table = TextTable::new();
+        for header in ["分组", "节点", "可用", "最佳med", "组内med"] {
+            table.header(header);
+        }
+        if download_size.is_some() {
+            table.header(format!("最佳速度{}", SpeedUnit::current().label()));
+        }
+        let other = (!other.is_empty()).then(|| ("other".to_string(), other));
+        for (name, members) in groups.into_iter().chain(other) {
+            let mut medians: Vec<f64> = members
+                .iter()
+                .filter_map(|r| match r.latency {
+                    LatencyResult::Success { median, .. } => Some(median),
+                    _ => None,
+                })
+                .collect();
+            medians.sort_unstable_by(|a, b| a.total_cmp(b));
+            let ms = |value: Option<f64>| value.map_or_else(|| "-".to_string(), decimal);
+            let mut row = vec![
+                Cell::from(name),
+                Cell::from(members.len().to_string()),
+                Cell::from(medians.len().to_string()),
+                Cell::from(ms(medians.first().copied())),
+                Cell::from(ms((!medians.is_empty()).then(|| median(&medians)))),
+            ];
+            if download_size.is_some() {
+                let best_speed = members
+                    .iter()
+                    .filter_map(|r| match r.speed {
+                        Some(SpeedResult::Success(speed)) => Some(speed),
+                        _ => None,
+                    })
+                    .max_by(|a, b| a.total_cmp(b));
+                row.push(Cell::from(best_speed.map_or_else(
+                    || "-".to_string(),
+                    |speed| decimal(SpeedUnit::current().convert(speed)),
+                )));
+            }
+            table.row(row);
+        }
+        out!("{}", table.render(table_style));
+    }
 
     // 总结
-    if let Some(size_mb) = download_mb {
+    CONSOLE_MUTED.store(false, Ordering::Relaxed);
+    if let Some(size) = download_size {
         let successful = results
             .iter()
             .filter(|r| matches!(r.speed, Some(SpeedResult::Success(_))))
             .count();
 
-        println!("\n📊 测试总结:");
-        println!("   总节点数: {}", results.len());
-        println!("   速度测试成功: {} 个", successful);
-        println!("   速度测试失败: {} 个", results.len() - successful);
-        println!("   测试文件大小: {} MB", size_mb);
+        outln!("\n📊 测试总结:");
+        outln!("   总节点数: {}", results.len());
+        outln!("   速度测试成功: {} 个", successful);
+        let too_slow = results
+            .iter()
+            .filter(|r| matches!(r.speed, Some(SpeedResult::TooSlow(_))))
+            .count();
+        if let Some(floor) = speed_floor {
+            outln!("   低于速度下限 ({floor} Mbps): {} 个", too_slow);
+        }
+        outln!(
+            "   速度测试失败: {} 个",
+            results.len() - successful - too_slow
+        );
+        outln!("   测试文件大小: {}", format_bytes(size));
+        outln!("   总耗时: {}", format_duration(total_elapsed));
+        if let Some((rescued, retried)) = rescued {
+            outln!("   重试挽回: {}/{} 个", rescued, retried);
+        }
     } else {
-        println!(
-            "\n📊 测试完成，共测试 {} 个节点（仅延迟测试）",
-            results.len()
+        outln!(
+            "\n📊 测试完成，共测试 {} 个节点（仅延迟测试），总耗时 {}",
+            results.len(),
+            format_duration(total_elapsed)
+        );
+        if let Some((rescued, retried)) = rescued {
+            outln!("   重试挽回: {}/{} 个", rescued, retried);
+        }
+    }
+    if let Some(budget) = retry_budget {
+        let used = budget - RETRY_BUDGET.load(Ordering::Relaxed);
+        outln!("   重试预算: 已用 {}/{} 次", used, budget);
+    }
+    if select == Selection::StableFast && !no_sort {
+        let lossy = results
+            .iter()
+            .filter(|r| r.loss_ratio(latency_count) > STABLE_FAST_MAX_LOSS)
+            .count();
+        outln!(
+            "   排序规则: stable-fast，丢包率超过 {:.0}% 的 {} 个节点排在最后，其余按中位延迟排序",
+            STABLE_FAST_MAX_LOSS * 100.0,
+            lossy
+        );
+    }
+    let latency_ok: Vec<(&NodeResult, f64)> = results
+        .iter()
+        .filter_map(|r| match r.latency {
+            LatencyResult::Success { median, .. } => Some((r, median)),
+            _ => None,
+        })
+        .collect();
+    outln!("   延迟测试成功: {}/{} 个", latency_ok.len(), results.len());
+    if let Some((fastest, median)) = latency_ok.iter().min_by(|a, b| a.1.total_cmp(&b.1)) {
+        outln!("   最低延迟: {} ({} ms)", fastest.tag, decimal(*median));
+    }
+    // 同一原因大量出现时多半是整体问题，例如大量超时说明上游拥塞
+    let mut failures: BTreeMap<Option<FailReason>, usize> = BTreeMap::new();
+    for reason in results.iter().filter_map(NodeResult::failure_reason) {
+        *failures.entry(reason).or_default() += 1;
+    }
+    if !failures.is_empty() {
+        outln!("   失败原因:");
+        for (reason, count) in &failures {
+            let label = reason.map_or_else(|| "未知".to_string(), |reason| reason.to_string());
+            outln!("     {} {:>4} 个", pad_end(&label, 14), count);
+        }
+    }
+    if all_unreachable {
+        let mut ports: Vec<u16> = socks_nodes.iter().map(|(_, port)| *port).collect();
+        ports.sort_unstable();
+        ports.dedup();
+        let ports = ports
+            .iter()
+            .map(u16::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        errln!(
+            "\n⚠️  全部 {} 个节点都无法连接，这通常不是节点本身的问题:",
+            total
         );
+        if AD_HOC_PROXIES.get().is_some() {
+            errln!("   - 确认 --socks 给出的地址正确，且本机能连到这些代理");
+        } else {
+            errln!("   - 确认 SingBox 正在运行，并且加载的是同一份配置");
+            errln!(
+                "   - 确认本机端口 {} 正在监听 (配置中的 listen_port)",
+                ports
+            );
+        }
+        errln!("   - 确认本机能够直接访问外网");
+    }
+
+    let mut exported: Vec<(usize, &NodeResult)> = (1..).zip(shown).collect();
+    // 问题节点单独成文件时从常规导出中去掉；不受 --top 限制，否则它们多半会被截掉
+    let mut problems: Vec<(usize, &NodeResult)> = Vec::new();
+    if failures_output.is_some() {
+        exported.retain(|(_, result)| result.severity() == 0);
+        problems = (1..)
+            .zip(&results)
+            .filter(|(_, result)| result.severity() > 0)
+            .collect();
+    }
+    if let Some(order) = export_sort {
+        order.apply(&mut exported);
+        order.apply(&mut problems);
+    }
+    if let Some(path) = &failures_output {
+        let rendered = format
+            .iter()
+            .find_map(|format| format.render(&problems, &columns))
+            .unwrap_or_else(|| render_json(&problems));
+        match fs::write(path, rendered) {
+            Ok(()) => outln!("💾 已将 {} 个问题节点写入 {}", problems.len(), path),
+            Err(e) => errln!("❌ 无法写入 {}: {}", path, e),
+        }
+    }
+    for format in format {
+        // 表格只在 --output-dir 时另存一份；控制台上已经显示过了
+        let rendered = match (format, &output_dir) {
+            (OutputFormat::Table, Some(_)) => Some(strip_ansi(&table_text)),
+            (OutputFormat::TableCompact, Some(_)) => Some(
+                compact_lines
+                    .iter()
+                    .map(|(line, _)| format!("{line}\n"))
+                    .collect(),
+            ),
+            _ => format.render(&exported, &columns),
+        };
+        let Some(rendered) = rendered else {
+            continue;
+        };
+        match &output_dir {
+            Some(dir) => {
+                let path =
+                    std::path::Path::new(dir).join(format!("results.{}", format.extension()));
+                if let Err(e) = fs::create_dir_all(dir).and_then(|_| fs::write(&path, rendered)) {
+                    errln!("❌ 无法写入 {}: {}", path.display(), e);
+                } else {
+                    outln!("💾 已写入 {}", path.display());
+                }
+            }
+            None => println!("{}", rendered.trim_end()),
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summarize(samples: &[f64]) -> LatencyResult {
+        let options = TestOptions {
+            min_valid: 1,
+            ..TestOptions::default()
+        };
+        summarize_latencies(samples, samples.len(), &options)
+    }
+
+    #[test]
+    fn median_of_odd_count_is_middle_value() {
+        assert_eq!(median(&[7.0]), 7.0);
+        assert_eq!(median(&[1.0, 2.0, 9.0]), 2.0);
+    }
+
+    #[test]
+    fn median_of_even_count_averages_middle_values() {
+        assert_eq!(median(&[1.0, 3.0]), 2.0);
+        assert_eq!(median(&[1.0, 2.0, 4.0, 100.0]), 3.0);
+    }
+
+    #[test]
+    fn summary_median_ignores_failed_attempts() {
+        // 失败的样本不计入，剩余 4 个有效样本取中间两个的平均
+        let samples = [40.0, f64::INFINITY, 10.0, 30.0, 20.0];
+        assert!(matches!(
+            summarize(&samples),
+            LatencyResult::Success { median, .. } if median == 25.0
+        ));
+    }
+
+    #[test]
+    fn summary_median_of_ten_samples() {
+        let samples: Vec<f64> = (1..=10).map(f64::from).collect();
+        assert!(matches!(
+            summarize(&samples),
+            LatencyResult::Success { median, .. } if median == 5.5
+        ));
+    }
+
+    #[test]
+    fn zero_elapsed_gives_finite_speed() {
+        for elapsed in [
+            Duration::ZERO,
+            Duration::from_nanos(1),
+            Duration::from_nanos(999),
+        ] {
+            let mbps = throughput_mbps(1 << 20, elapsed);
+            assert!(mbps.is_finite() && mbps > 0.0);
+        }
+        assert_eq!(throughput_mbps(0, Duration::ZERO), 0.0);
+    }
+
+    #[test]
+    fn zero_latency_samples_summarize_finitely() {
+        let LatencyResult::Success {
+            median,
+            average,
+            minimum,
+            maximum,
+            ..
+        } = summarize(&[0.0, 0.0, f64::INFINITY, 0.0])
+        else {
+            panic!("zero samples are valid measurements");
+        };
+        assert!([median, average, minimum, maximum]
+            .iter()
+            .all(|value| *value == 0.0));
+    }
+
+    #[test]
+    fn locale_groups_thousands() {
+        let de: NumberLocale = "de_DE.UTF-8".parse().unwrap();
+        assert_eq!(de.apply("1234567.891".to_string()), "1.234.567,891");
+        assert_eq!(de.apply("-1234.5".to_string()), "-1.234,5");
+        assert_eq!(de.apply("+999.00".to_string()), "+999,00");
+        let en: NumberLocale = "en".parse().unwrap();
+        assert_eq!(en.apply("1000".to_string()), "1,000");
+        assert_eq!(en.apply("inf".to_string()), "inf");
+    }
+
+    /// Serve one response per connection: `head` (status line and headers), then
+    /// `body_len` bytes in `chunks` pieces spaced `pause` apart, then hang up
+    async fn serve_download(
+        head: &'static str,
+        body_len: usize,
+        chunks: usize,
+        pause: Duration,
+    ) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request).await;
+                let _ = stream.write_all(head.as_bytes()).await;
+                let chunk = vec![b'x'; body_len / chunks];
+                for _ in 0..chunks {
+                    tokio::time::sleep(pause).await;
+                    if stream.write_all(&chunk).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        url
+    }
+
+    fn local_client() -> Client {
+        Client::builder().no_proxy().build().unwrap()
+    }
+
+    #[tokio::test]
+    async fn download_speed_matches_served_rate() {
+        // 200 KB 分 10 块、每块间隔 50 ms，约 0.5 s 下载完，即约 3.3 Mbps
+        let head = "HTTP/1.1 200 OK\r\ncontent-length: 204800\r\nconnection: close\r\n\r\n";
+        let url = serve_download(head, 204_800, 10, Duration::from_millis(50)).await;
+        let (speed, _) =
+            measure_download(&local_client(), &url, RetryPolicy::default(), None).await;
+        let SpeedResult::Success(mbps) = speed else {
+            panic!("download should succeed, got {speed:?}");
+        };
+        let expected = 204_800.0 * 8.0 / 1_000_000.0 / 0.5;
+        assert!(
+            (expected * 0.5..=expected * 1.5).contains(&mbps),
+            "{mbps} Mbps is not close to {expected} Mbps"
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_download_is_cut_short() {
+        // 每 0.5 s 发送 1 KB，全部发完需要 50 s
+        let head = "HTTP/1.1 200 OK\r\ncontent-length: 102400\r\n\r\n";
+        let url = serve_download(head, 102_400, 100, Duration::from_millis(500)).await;
+        let started = Instant::now();
+        let (speed, _) =
+            measure_download(&local_client(), &url, RetryPolicy::default(), Some(10.0)).await;
+        assert!(matches!(speed, SpeedResult::TooSlow(_)), "got {speed:?}");
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn connect_request_carries_basic_credentials() {
+        let target: ProbeHost = "example.com:443".parse().unwrap();
+        let user = InboundUser {
+            username: "alice".to_string(),
+            password: "s3cret".to_string(),
+        };
+        assert_eq!(
+            connect_request(&target, Some(&user)),
+            "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\
+             Proxy-Authorization: Basic YWxpY2U6czNjcmV0\r\n\r\n"
+        );
+        assert_eq!(
+            connect_request(&target, None),
+            "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_after_past_budget_is_not_waited_for() {
+        let head = "HTTP/1.1 429 Too Many Requests\r\nretry-after: 30\r\ncontent-length: 0\r\n\r\n";
+        let url = serve_download(head, 0, 1, Duration::ZERO).await;
+        let retry = RetryPolicy {
+            rate_limited: true,
+            deadline: Some(Instant::now() + Duration::from_secs(5)),
+            ..RetryPolicy::default()
+        };
+        let started = Instant::now();
+        let (speed, _) = measure_download(&local_client(), &url, retry, None).await;
+        assert!(matches!(speed, SpeedResult::Failed { .. }), "got {speed:?}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn segmented_download_needs_range_support() {
+        let segmented = |url: String| async move {
+            measure_segmented(
+                &local_client(),
+                &url,
+                90_000,
+                3,
+                RetryPolicy::default(),
+                None,
+            )
+            .await
+        };
+        // 每个请求（包括 HEAD）都回应同一段 206，足够验证分段流程
+        let head = "HTTP/1.1 206 Partial Content\r\naccept-ranges: bytes\r\ncontent-length: 30000\r\nconnection: close\r\n\r\n";
+        let url = serve_download(head, 30_000, 1, Duration::ZERO).await;
+        let (speed, _) = segmented(url).await.expect("server supports ranges");
+        assert!(matches!(speed, SpeedResult::Success(_)), "got {speed:?}");
+
+        // 不支持 Range 的服务器退回单连接下载
+        let head = "HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n";
+        let url = serve_download(head, 0, 1, Duration::ZERO).await;
+        assert!(segmented(url).await.is_none());
+
+        // HEAD 连不上是节点的失败，不是不支持 Range
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", closed.local_addr().unwrap());
+        drop(closed);
+        let (speed, _) = segmented(url).await.expect("a failed HEAD is reported");
+        assert!(
+            matches!(
+                speed,
+                SpeedResult::Failed {
+                    reason: FailReason::Connect,
+                    ..
+                }
+            ),
+            "got {speed:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn truncated_download_is_a_stream_error() {
+        // 声明 1 MB 但只发送 10 KB 就断开
+        let head = "HTTP/1.1 200 OK\r\ncontent-length: 1048576\r\n\r\n";
+        let url = serve_download(head, 10_240, 1, Duration::ZERO).await;
+        let (speed, peak) =
+            measure_download(&local_client(), &url, RetryPolicy::default(), None).await;
+        assert!(matches!(
+            speed,
+            SpeedResult::Failed {
+                reason: FailReason::Stream,
+                ..
+            }
+        ));
+        assert_eq!(peak, None);
+    }
+
+    #[tokio::test]
+    async fn error_status_is_an_http_failure() {
+        let head = "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n";
+        let url = serve_download(head, 0, 1, Duration::ZERO).await;
+        let (speed, _) =
+            measure_download(&local_client(), &url, RetryPolicy::default(), None).await;
+        assert!(matches!(
+            speed,
+            SpeedResult::Failed {
+                reason: FailReason::Http,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn handshake_failure_is_a_tls_error() {
+        // 对 https 请求回以明文 HTTP，握手在 TLS 层失败
+        let head = "HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n";
+        let url = serve_download(head, 0, 1, Duration::ZERO).await;
+        let url = url.replace("http://", "https://");
+        let (speed, _) =
+            measure_download(&local_client(), &url, RetryPolicy::default(), None).await;
+        assert!(
+            matches!(
+                speed,
+                SpeedResult::Failed {
+                    reason: FailReason::Tls,
+                    ..
+                }
+            ),
+            "expected a TLS failure, got {speed:?}"
+        );
+    }
+
+    #[test]
+    fn health_moves_towards_recent_runs() {
+        let mut health = NodeHealth::default();
+        health.update(&ranked_node("a", Some(100.0), None), 0.5);
+        assert_eq!((health.success_rate, health.latency), (1.0, Some(100.0)));
+        health.update(&ranked_node("a", None, None), 0.5);
+        assert_eq!((health.success_rate, health.latency), (0.5, Some(100.0)));
+        health.update(&ranked_node("a", Some(200.0), None), 0.5);
+        assert_eq!((health.success_rate, health.latency), (0.75, Some(150.0)));
+        assert_eq!(health.runs, 3);
+    }
+
+    #[test]
+    fn strip_ansi_keeps_only_text() {
+        assert_eq!(strip_ansi("\x1b[1;32mUS-01\x1b[0m 52 ms"), "US-01 52 ms");
+    }
+
+    #[test]
+    fn wildcard_listen_is_reachable_locally() {
+        for listen in ["127.0.0.1", "::1", "localhost", "0.0.0.0", "::"] {
+            assert!(is_loopback_listen(listen), "{listen}");
+        }
+        for listen in ["192.168.1.2", "fe80::1", "example.com"] {
+            assert!(!is_loopback_listen(listen), "{listen}");
+        }
+    }
+
+    fn ranked_node(tag: &str, median: Option<f64>, speed: Option<SpeedResult>) -> NodeResult {
+        let latency = match median {
+            Some(median) => LatencyResult::Success {
+                median,
+                average: median,
+                minimum: median,
+                maximum: median,
+                trimmed_mean: None,
+                ci: None,
+            },
+            None => LatencyResult::AllFailed,
+        };
+        NodeResult {
+            speed,
+            ..NodeResult::failed(tag, 1, latency)
+        }
+    }
+
+    fn ranked_tags(mut results: Vec<NodeResult>, by_speed: bool) -> Vec<String> {
+        results.sort_by(|a, b| rank_order(a, b, by_speed));
+        results.into_iter().map(|r| r.tag).collect()
+    }
+
+    #[test]
+    fn rank_order_by_speed_then_latency() {
+        let failed = || Some(SpeedResult::failed(FailReason::Timeout, "no response"));
+        let results = vec![
+            ranked_node("no-speed", Some(10.0), None),
+            ranked_node("slow", Some(50.0), Some(SpeedResult::Success(20.0))),
+            ranked_node("too-slow", Some(5.0), Some(SpeedResult::TooSlow(90.0))),
+            ranked_node("failed", Some(30.0), failed()),
+            ranked_node("fast", Some(80.0), Some(SpeedResult::Success(100.0))),
+            ranked_node("dead", None, failed()),
+        ];
+        // 有成功速度的按速度排在前面，其余（失败、过慢、未测）交给延迟
+        let expected = ["fast", "slow", "too-slow", "no-speed", "failed", "dead"];
+        assert_eq!(ranked_tags(results.clone(), true), expected);
+
+        // 不按速度排名时只看延迟
+        let expected = ["too-slow", "no-speed", "failed", "slow", "fast", "dead"];
+        assert_eq!(ranked_tags(results, false), expected);
+    }
+
+    #[test]
+    fn rank_order_puts_every_failure_after_success() {
+        let mut results = vec![ranked_node("ok", Some(900.0), None)];
+        for (tag, latency) in [
+            ("unstable", LatencyResult::Unstable(3, 10)),
+            ("all-failed", LatencyResult::AllFailed),
+            ("session", LatencyResult::SessionError("proxy".to_string())),
+            ("tls", LatencyResult::TlsError("certificate".to_string())),
+        ] {
+            results.push(NodeResult::failed(tag, 1, latency));
+        }
+        // 失败之间不分先后，按 tag 排列
+        let expected = ["ok", "all-failed", "session", "tls", "unstable"];
+        assert_eq!(ranked_tags(results, true), expected);
+    }
+
+    #[test]
+    fn rank_order_ties_break_by_tag_in_any_input_order() {
+        let speed = || Some(SpeedResult::Success(50.0));
+        let results = vec![
+            ranked_node("c", Some(10.0), speed()),
+            ranked_node("a", Some(10.0), speed()),
+            ranked_node("b", Some(10.0), speed()),
+        ];
+        let mut reversed = results.clone();
+        reversed.reverse();
+        assert_eq!(ranked_tags(results, true), ["a", "b", "c"]);
+        assert_eq!(ranked_tags(reversed, true), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn failures_first_orders_by_severity_then_rank() {
+        let mut results = [
+            ranked_node("fast", Some(20.0), Some(SpeedResult::Success(90.0))),
+            ranked_node("slow", Some(10.0), Some(SpeedResult::TooSlow(5.0))),
+            ranked_node("ok", Some(30.0), Some(SpeedResult::Success(40.0))),
+            NodeResult::failed("dead", 1, LatencyResult::AllFailed),
+            NodeResult::failed("flaky", 1, LatencyResult::Unstable(3, 10)),
+        ];
+        results.sort_by(|a, b| rank_order(a, b, true));
+        results.sort_by_key(|r| std::cmp::Reverse(r.severity()));
+        let tags: Vec<_> = results.iter().map(|r| r.tag.as_str()).collect();
+        assert_eq!(tags, ["dead", "flaky", "slow", "fast", "ok"]);
+    }
+
+    #[test]
+    fn compact_line_shows_status_for_failures() {
+        let ok = ranked_node("US-01", Some(52.0), Some(SpeedResult::Success(120.0)));
+        assert_eq!(ok.compact_line(1), "#1 52.00ms 120.00Mbps [US-01]");
+        let dead = NodeResult::failed("JP-02", 2, LatencyResult::AllFailed);
+        assert_eq!(dead.compact_line(2), "#2 FAILED [JP-02]");
+    }
+
+    #[test]
+    fn confidence_interval_uses_t_distribution() {
+        let options = TestOptions {
+            min_valid: 1,
+            confidence_interval: true,
+            ..TestOptions::default()
+        };
+        let ci = |samples: &[f64]| match summarize_latencies(samples, samples.len(), &options) {
+            LatencyResult::Success { ci, .. } => ci,
+            other => panic!("unexpected {other:?}"),
+        };
+        // 均值 12，标准差 2，自由度 2 的 t 值 4.303
+        let half = ci(&[10.0, 12.0, 14.0, f64::INFINITY]).unwrap();
+        assert!((half - 4.303 * 2.0 / 3f64.sqrt()).abs() < 1e-9);
+        // 单个样本无从估计离散程度
+        assert_eq!(ci(&[10.0]), None);
+    }
+
+    #[tokio::test]
+    async fn only_transient_failures_are_retryable() {
+        let client = local_client();
+        for (head, retryable) in [
+            (
+                "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n",
+                true,
+            ),
+            (
+                "HTTP/1.1 429 Too Many Requests\r\ncontent-length: 0\r\n\r\n",
+                true,
+            ),
+            ("HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n", false),
+            (
+                "HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\n\r\n",
+                false,
+            ),
+        ] {
+            let url = serve_download(head, 0, 1, Duration::ZERO).await;
+            let result = timeout(Duration::from_secs(5), client.get(&url).send()).await;
+            assert_eq!(is_retryable(&result), retryable, "{head}");
+        }
+
+        // 连接建立后一直没有响应，超时可以重试
+        let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", silent.local_addr().unwrap());
+        let result = timeout(Duration::from_millis(200), client.get(&url).send()).await;
+        assert!(is_retryable(&result));
+    }
+
+    #[test]
+    fn export_sort_keeps_table_rank() {
+        let results = [
+            NodeResult::failed("b", 2, LatencyResult::AllFailed),
+            NodeResult::failed("c", 1, LatencyResult::AllFailed),
+            NodeResult::failed("a", 3, LatencyResult::AllFailed),
+        ];
+        let mut rows: Vec<(usize, &NodeResult)> = (1..).zip(&results).collect();
+        ExportSort::Tag.apply(&mut rows);
+        let order: Vec<(usize, &str)> = rows
+            .iter()
+            .map(|(rank, r)| (*rank, r.tag.as_str()))
+            .collect();
+        assert_eq!(order, [(3, "a"), (1, "b"), (2, "c")]);
+        ExportSort::Rank.apply(&mut rows);
+        assert_eq!(rows[0].1.tag, "b");
+    }
+
+    #[test]
+    fn peak_window_skips_slow_start() {
+        // 第 1 秒只下载 100 字节，之后每秒 1000 字节
+        let progress = [(0.0, 0), (1.0, 100), (2.0, 1100), (3.0, 2100), (4.0, 3100)];
+        assert_eq!(peak_window_rate(&progress, 2.0), Some(1000.0));
+    }
+
+    #[test]
+    fn peak_window_needs_a_full_window() {
+        let progress = [(0.0, 0), (0.5, 500), (1.5, 1500)];
+        assert_eq!(peak_window_rate(&progress, 2.0), None);
+    }
+}